@@ -0,0 +1,105 @@
+// A minimal MCP server speaking JSON-RPC over stdio, used by the tests of
+// the MCP client. It offers an `echo` tool, a failing `fail` tool and a
+// single text resource. Started with `exit` it quits after the handshake,
+// with `hang` it never answers anything but the handshake.
+
+use std::io::{self, BufRead, Write};
+use std::{env, thread, time::Duration};
+
+use serde_json::{json, Value};
+
+pub fn result(request: &Value) -> Result<Value, (i64, String)> {
+    let params = &request["params"];
+    match request["method"].as_str().unwrap_or_default() {
+        "initialize" => Ok(json!({
+            "protocolVersion": params["protocolVersion"],
+            "capabilities": { "tools": {}, "resources": {} },
+            "serverInfo": { "name": "fake", "version": "0.1.0" }
+        })),
+        "tools/list" => Ok(json!({
+            "tools": [
+                {
+                    "name": "echo",
+                    "description": "Send back the text",
+                    "inputSchema": {
+                        "type": "object",
+                        "properties": { "text": { "type": "string" } },
+                        "required": ["text"]
+                    }
+                },
+                { "name": "fail", "description": "Always fails", "inputSchema": { "type": "object" } }
+            ]
+        })),
+        "tools/call" => match params["name"].as_str() {
+            Some("echo") => Ok(json!({
+                "content": [{ "type": "text", "text": params["arguments"]["text"] }]
+            })),
+            Some("fail") => Ok(json!({
+                "content": [{ "type": "text", "text": "Something broke" }],
+                "isError": true
+            })),
+            _ => Err((-32602, "Unknown tool".to_string())),
+        },
+        "resources/list" => Ok(json!({
+            "resources": [{ "uri": "fake://notes", "name": "notes", "mimeType": "text/plain" }]
+        })),
+        "resources/read" => match params["uri"].as_str() {
+            Some("fake://notes") => Ok(json!({
+                "contents": [{ "uri": "fake://notes", "text": "Remember the milk" }]
+            })),
+            _ => Err((-32002, "Resource not found".to_string())),
+        },
+        method => Err((-32601, format!("Unknown method {method}"))),
+    }
+}
+
+fn main() {
+    let mode = env::args().nth(1).unwrap_or_default();
+
+    // Logs go to stderr, which must not get in the way of the messages
+    eprintln!("fake MCP server started");
+
+    let mut stdout = io::stdout();
+    for line in io::stdin().lock().lines() {
+        let request: Value = serde_json::from_str(&line.unwrap()).unwrap();
+        if request["method"] == "notifications/initialized" && mode == "exit" {
+            return;
+        }
+
+        // Notifications and responses to our own requests get no response
+        let (Some(id), Some(_)) = (request.get("id"), request.get("method")) else {
+            continue;
+        };
+        if request["method"] != "initialize" && mode == "hang" {
+            continue;
+        }
+
+        // Check that the client answers requests coming from the server
+        if request["method"] == "tools/list" {
+            writeln!(stdout, r#"{{"jsonrpc":"2.0","id":"s1","method":"ping"}}"#).unwrap();
+            writeln!(
+                stdout,
+                r#"{{"jsonrpc":"2.0","method":"notifications/message"}}"#
+            )
+            .unwrap();
+        }
+
+        let response = match result(&request) {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err((code, message)) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": code, "message": message }
+            }),
+        };
+
+        // Split in two writes, the client reads whole lines
+        let response = format!("{response}\n");
+        let (head, tail) = response.split_at(response.len() / 2);
+        write!(stdout, "{head}").unwrap();
+        stdout.flush().unwrap();
+        thread::sleep(Duration::from_millis(5));
+        write!(stdout, "{tail}").unwrap();
+        stdout.flush().unwrap();
+    }
+}
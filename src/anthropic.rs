@@ -0,0 +1,563 @@
+use crate::chat::{self, Data, Settings};
+use crate::conversation::{self, Callback, Conversation, Role, State};
+use crate::error::{Details, OctoError};
+use crate::provider::Auth;
+
+use std::ops::AddAssign;
+
+use anyhow::{anyhow, Result};
+use reqwest::Client;
+use reqwest_eventsource::{Event, EventSource};
+use serde::{Deserialize, Serialize};
+
+use async_trait::async_trait;
+use tokio_stream::StreamExt;
+
+/// The Messages API version this implementation speaks.
+const VERSION: &str = "2023-06-01";
+
+#[derive(Serialize, Debug)]
+struct Message {
+    role: String,
+    content: String,
+}
+
+#[derive(Serialize, Debug)]
+struct Metadata {
+    user_id: String,
+}
+
+#[derive(Serialize, Debug)]
+struct Request {
+    model: String,
+    messages: Vec<Message>,
+
+    /// The system prompt, which is not part of the messages.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<String>,
+
+    max_tokens: i64,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<Metadata>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop_sequences: Option<Vec<String>>,
+
+    stream: bool,
+    temperature: f64,
+
+    /// Only sent when changed, as newer models refuse both `temperature`
+    /// and `top_p`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f64>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Usage {
+    #[serde(default)]
+    input_tokens: i64,
+
+    #[serde(default)]
+    output_tokens: i64,
+}
+
+impl From<Usage> for conversation::Usage {
+    fn from(usage: Usage) -> Self {
+        conversation::Usage {
+            prompt_tokens: usage.input_tokens,
+            completion_tokens: usage.output_tokens,
+            total_tokens: usage.input_tokens + usage.output_tokens,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct MessageStart {
+    id: String,
+    model: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    usage: Option<Usage>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentDelta {
+    TextDelta {
+        text: String,
+    },
+    InputJsonDelta {
+        partial_json: String,
+    },
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Deserialize, Debug)]
+struct MessageDelta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop_reason: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop_sequence: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ErrorObject {
+    #[serde(rename = "type")]
+    type_: String,
+    message: String,
+}
+
+impl From<ErrorObject> for Details {
+    fn from(error: ErrorObject) -> Self {
+        Details {
+            message: error.message,
+            type_: Some(error.type_),
+            ..Default::default()
+        }
+    }
+}
+
+/// Server-sent events of the Messages streaming API.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Response {
+    MessageStart {
+        message: MessageStart,
+    },
+    ContentBlockStart {
+        index: i64,
+    },
+    ContentBlockDelta {
+        index: i64,
+        delta: ContentDelta,
+    },
+    ContentBlockStop {
+        index: i64,
+    },
+    MessageDelta {
+        delta: MessageDelta,
+
+        #[serde(skip_serializing_if = "Option::is_none")]
+        usage: Option<Usage>,
+    },
+    MessageStop,
+    Ping,
+    Error {
+        error: ErrorObject,
+    },
+    #[serde(other)]
+    Unknown,
+}
+
+/// Map Anthropic's stop reason onto the conversation state.
+fn finish(reason: &str) -> State<'static> {
+    match reason {
+        "max_tokens" => State::OutOfCharacters,
+        "tool_use" => State::ToolCalls,
+        _ => State::Stop,
+    }
+}
+
+/// Conversation through the Anthropic Messages API.
+pub struct Anthropic {
+    client: Client,
+    api_key: String,
+    url: String,
+    model: String,
+    settings: Settings,
+    history: Vec<Data>,
+    auth: Auth,
+    metadata: conversation::Metadata,
+}
+
+impl Anthropic {
+    pub fn new(api_key: &str, url: &str, model: &str, settings: &Settings) -> Self {
+        Anthropic {
+            client: Client::new(),
+            api_key: api_key.to_string(),
+            url: url.to_string(),
+            model: model.to_string(),
+            settings: settings.clone(),
+            history: vec![],
+            auth: Auth::Header("x-api-key"),
+            metadata: conversation::Metadata::default(),
+        }
+    }
+
+    pub fn auth(mut self, auth: Auth) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    fn request(&self) -> Request {
+        let messages = self
+            .history
+            .iter()
+            .filter(|data| data.role.as_deref() != Some("system"))
+            .map(|data| Message {
+                role: match data.role.as_deref() {
+                    Some("assistant") => "assistant",
+                    _ => "user",
+                }
+                .to_string(),
+                content: data.content.clone().unwrap_or_default(),
+            })
+            .collect();
+
+        Request {
+            model: self.model.clone(),
+            messages,
+            system: chat::system(&self.history).map(str::to_string),
+            max_tokens: self.settings.max_tokens,
+            metadata: self
+                .settings
+                .user
+                .clone()
+                .map(|user_id| Metadata { user_id }),
+            stop_sequences: self.settings.stop.clone(),
+            stream: true,
+            temperature: self.settings.temperature,
+            top_p: (self.settings.top_p != Settings::default().top_p)
+                .then_some(self.settings.top_p),
+        }
+    }
+}
+
+#[async_trait]
+impl Conversation for Anthropic {
+    fn build(&mut self, role: Role, message: &str) -> &mut dyn Conversation {
+        self.history.push(Data::new(role, message));
+        self
+    }
+
+    fn set_system(&mut self, prompt: &str) -> &mut dyn Conversation {
+        chat::set_system(&mut self.history, prompt);
+        self
+    }
+
+    fn system(&self) -> Option<&str> {
+        chat::system(&self.history)
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn set_model(&mut self, model: &str) {
+        self.model = model.to_string();
+    }
+
+    fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    fn settings_mut(&mut self) -> &mut Settings {
+        &mut self.settings
+    }
+
+    fn history(&self) -> &[Data] {
+        &self.history
+    }
+
+    fn restore(&mut self, history: Vec<Data>) {
+        self.history = history;
+    }
+
+    fn metadata(&self) -> &conversation::Metadata {
+        &self.metadata
+    }
+
+    async fn send(&mut self, f: Callback<'_>) -> Result<()> {
+        // The Messages API has no JSON mode, rather than replying with
+        // whatever the model writes, refuse
+        if self.settings.json {
+            return Err(anyhow!(
+                "JSON mode is not supported by Anthropic, turn it off with /json off"
+            ));
+        }
+
+        let builder = self
+            .client
+            .post(&self.url)
+            .header("anthropic-version", VERSION)
+            .json(&self.request());
+
+        let builder = self.auth.apply(builder, &self.api_key);
+
+        // As for the OpenAI dialect, we always stream and only forward the
+        // chunks if the user asked for it.
+        let mut text = String::new();
+        self.metadata = conversation::Metadata::default();
+
+        let mut es = EventSource::new(builder)?;
+        while let Some(event) = es.next().await {
+            let data = match event {
+                Ok(Event::Open) => {
+                    f(State::Start);
+                    continue;
+                }
+                Ok(Event::Message(message)) => message.data,
+                Err(reqwest_eventsource::Error::InvalidStatusCode(status, response)) => {
+                    es.close();
+                    let headers = response.headers().clone();
+                    let body = response.text().await.unwrap_or_default();
+                    let said = match serde_json::from_str::<Response>(&body) {
+                        Ok(Response::Error { error }) => Some(error),
+                        _ => None,
+                    };
+                    let mut details = Details::response(status, &headers, body);
+                    if let Some(error) = said {
+                        details = details.with(error.into());
+                    }
+                    return Err(OctoError::from(details).into());
+                }
+                Err(reqwest_eventsource::Error::StreamEnded) => {
+                    es.close();
+                    return Err(OctoError::ended().into());
+                }
+                Err(error) => {
+                    es.close();
+                    return Err(OctoError::network(error).into());
+                }
+            };
+
+            match serde_json::from_str::<Response>(&data).map_err(OctoError::protocol)? {
+                Response::ContentBlockDelta {
+                    delta: ContentDelta::TextDelta { text: chunk },
+                    ..
+                } => {
+                    text.add_assign(&chunk);
+
+                    // Only send message chunks if the user requested stream
+                    if self.settings.stream {
+                        f(State::Message(&chunk));
+                    }
+                }
+                Response::MessageStart { message } => {
+                    self.metadata.model = Some(message.model);
+                    if let Some(usage) = message.usage {
+                        self.metadata.add_usage(usage.into());
+                    }
+                }
+                Response::MessageDelta { delta, usage } => {
+                    // Counts here are running totals for the message, and
+                    // replace the ones from its start rather than add up
+                    if let Some(delta) = usage {
+                        let usage = self.metadata.usage.get_or_insert_with(Default::default);
+                        if delta.input_tokens > 0 {
+                            usage.prompt_tokens = delta.input_tokens;
+                        }
+                        usage.completion_tokens = delta.output_tokens;
+                        usage.total_tokens = usage.prompt_tokens + usage.completion_tokens;
+                    }
+                    if delta.stop_reason.is_some() {
+                        self.metadata.finish_reason = delta.stop_reason;
+                    }
+                }
+                Response::MessageStop => {
+                    // The whole reply at once, when it was not streamed
+                    if !self.settings.stream {
+                        f(State::Message(&text));
+                    }
+                    if let Some(reason) = &self.metadata.finish_reason {
+                        f(finish(reason));
+                    }
+                    self.history.push(Data::new(Role::Assistant, &text));
+                    if let Some(usage) = self.metadata.usage {
+                        f(State::Usage(usage));
+                    }
+
+                    f(State::Done);
+                    es.close();
+                    return Ok(());
+                }
+                Response::Error { error } => {
+                    // Overloaded errors may also come once the stream started,
+                    // and are then tried again like a 529
+                    es.close();
+                    return Err(OctoError::from(Details::from(error)).into());
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{process::ExitCode, sync::Mutex};
+
+    use crate::pricing::{Prices, Tally};
+
+    use serde_json::json;
+    use wiremock::{
+        matchers::{body_partial_json, header, method},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    fn anthropic(server: &MockServer, stream: bool) -> Anthropic {
+        let settings = Settings {
+            stream,
+            max_tokens: 64,
+            ..Default::default()
+        };
+
+        let mut anthropic = Anthropic::new("key", &server.uri(), "claude", &settings);
+        anthropic.set_system("Be brief").build(Role::User, "Hello");
+        anthropic
+    }
+
+    async fn transcript(server: &MockServer, status: u16, fixture: &str) {
+        Mock::given(method("POST"))
+            .and(header("x-api-key", "key"))
+            .and(header("anthropic-version", VERSION))
+            .and(body_partial_json(json!({
+                "model": "claude",
+                "system": "Be brief",
+                "messages": [{ "role": "user", "content": "Hello" }],
+                "max_tokens": 64,
+                "stream": true
+            })))
+            .respond_with(ResponseTemplate::new(status).set_body_raw(fixture, "text/event-stream"))
+            .mount(server)
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_stream_messages() {
+        let server = MockServer::start().await;
+        transcript(
+            &server,
+            200,
+            include_str!("../tests/fixtures/anthropic/stream.sse"),
+        )
+        .await;
+
+        let states = Mutex::new(vec![]);
+        let mut anthropic = anthropic(&server, true);
+        anthropic
+            .send(&|state| states.lock().unwrap().push(state.to_string()))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            *states.lock().unwrap(),
+            [
+                "start",
+                "message: Hi",
+                "message:  there!",
+                "stop",
+                "usage: 12 + 4 tokens",
+                "done"
+            ]
+        );
+        assert_eq!(anthropic.history()[2].content.as_deref(), Some("Hi there!"));
+
+        // Without stream the reply comes once, complete, before it stops
+        let states = Mutex::new(vec![]);
+        self::anthropic(&server, false)
+            .send(&|state| states.lock().unwrap().push(state.to_string()))
+            .await
+            .unwrap();
+        assert_eq!(
+            *states.lock().unwrap(),
+            [
+                "start",
+                "message: Hi there!",
+                "stop",
+                "usage: 12 + 4 tokens",
+                "done"
+            ]
+        );
+
+        // Charged once for the 4 output tokens, not for 1 + 4
+        let mut tally = Tally::new(Prices::default());
+        let cost = tally.add("claude-3-opus", anthropic.metadata().usage.unwrap());
+        assert_eq!(tally.total().total_tokens, 16);
+        assert_eq!(cost, Some((12.0 * 15.0 + 4.0 * 75.0) / 1_000_000.0));
+    }
+
+    #[test]
+    fn test_request_top_p() {
+        let mut anthropic =
+            Anthropic::new("key", "http://localhost", "claude", &Settings::default());
+        let request = serde_json::to_value(anthropic.request()).unwrap();
+        assert_eq!(request["temperature"], 1.0);
+        assert_eq!(request.get("top_p"), None);
+
+        anthropic.settings_mut().top_p = 0.9;
+        let request = serde_json::to_value(anthropic.request()).unwrap();
+        assert_eq!(request["top_p"], 0.9);
+    }
+
+    #[tokio::test]
+    async fn test_stop_reasons() {
+        for (fixture, state, usage) in [
+            (
+                include_str!("../tests/fixtures/anthropic/max_tokens.sse"),
+                "length",
+                "usage: 12 + 64 tokens",
+            ),
+            (
+                include_str!("../tests/fixtures/anthropic/tool_use.sse"),
+                "tool_calls",
+                "usage: 40 + 20 tokens",
+            ),
+        ] {
+            let server = MockServer::start().await;
+            transcript(&server, 200, fixture).await;
+
+            let states = Mutex::new(vec![]);
+            anthropic(&server, false)
+                .send(&|state| states.lock().unwrap().push(state.to_string()))
+                .await
+                .unwrap();
+
+            let states = states.lock().unwrap();
+            assert_eq!(states[states.len() - 3], state, "{states:?}");
+            assert_eq!(states[states.len() - 2], usage);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_json_mode() {
+        let server = MockServer::start().await;
+        let mut anthropic = anthropic(&server, false);
+        anthropic.settings_mut().json = true;
+
+        let error = anthropic.send(&|_| {}).await.unwrap_err();
+        assert!(error.to_string().starts_with("JSON mode is not supported"));
+        assert!(server.received_requests().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_error_events() {
+        for (status, fixture, code) in [
+            (
+                200,
+                include_str!("../tests/fixtures/anthropic/error.sse"),
+                8,
+            ),
+            (
+                401,
+                include_str!("../tests/fixtures/anthropic/error.json"),
+                5,
+            ),
+        ] {
+            let server = MockServer::start().await;
+            transcript(&server, status, fixture).await;
+
+            let result = anthropic(&server, true).send(&|_| {}).await;
+            let error = result.unwrap_err();
+            let error = error.downcast_ref::<OctoError>().unwrap();
+            assert_eq!(error.exit_code(), ExitCode::from(code), "{error}");
+        }
+    }
+}
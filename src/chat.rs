@@ -1,6 +1,10 @@
-use crate::conversation::{Conversation, Role, State};
+use crate::conversation::{Callback, Conversation, Metadata, Role, State, Usage};
+use crate::error::{Details, OctoError};
+use crate::provider::Auth;
+use crate::retry::Retry;
+use crate::tools::Tools;
 
-use std::{collections::HashMap, ops::AddAssign};
+use std::collections::HashMap;
 
 use anyhow::{anyhow, Result};
 use reqwest::Client;
@@ -32,23 +36,65 @@ struct Logprobs {
     content: Option<Vec<Content>>,
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
-struct Function {
-    name: String,
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub(crate) struct Function {
+    /// Streamed tool calls only carry the name in their first chunk.
+    #[serde(default)]
+    pub(crate) name: String,
+
     #[serde(skip_serializing_if = "Option::is_none")]
-    arguments: Option<String>,
+    pub(crate) arguments: Option<String>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     description: Option<String>,
+
+    /// JSON schema of the function arguments.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parameters: Option<serde_json::Value>,
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
-struct ToolCall {
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub(crate) struct ToolCall {
+    /// Position of the call, only set on streamed chunks.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    index: Option<usize>,
+
+    #[serde(default)]
     id: String,
 
-    #[serde(rename = "type")]
+    #[serde(rename = "type", default)]
     type_: String,
-    function: Function,
+    pub(crate) function: Function,
+}
+
+/// Merge streamed tool call chunks into the calls they belong to.
+fn accumulate(calls: &mut Vec<ToolCall>, chunks: &[ToolCall]) {
+    for chunk in chunks {
+        // Indexes past the next call start a new one, rather than growing
+        // the calls by whatever the index says
+        let index = chunk.index.unwrap_or(calls.len()).min(calls.len());
+        if index == calls.len() {
+            calls.push(ToolCall::default());
+        }
+
+        let call = &mut calls[index];
+        if !chunk.id.is_empty() {
+            call.id.clone_from(&chunk.id);
+        }
+        if !chunk.type_.is_empty() {
+            call.type_.clone_from(&chunk.type_);
+        }
+        // Some providers repeat the name in every chunk
+        if !chunk.function.name.is_empty() {
+            call.function.name.clone_from(&chunk.function.name);
+        }
+        if let Some(arguments) = &chunk.function.arguments {
+            call.function
+                .arguments
+                .get_or_insert_with(String::new)
+                .push_str(arguments);
+        }
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -71,35 +117,60 @@ struct Choice {
     text: Option<String>,
 }
 
+/// A message in the conversation history.
 #[derive(Deserialize, Serialize, Debug, Clone)]
-struct Data {
+pub struct Data {
     /// The role of the author.
     #[serde(skip_serializing_if = "Option::is_none")]
-    role: Option<String>,
+    pub(crate) role: Option<String>,
 
     /// The content of the message.
     #[serde(skip_serializing_if = "Option::is_none")]
-    content: Option<String>,
+    pub(crate) content: Option<String>,
 
     /// An optional name for the participant. Provides the model information
     /// to differentiate between participants of the same role.
     #[serde(skip_serializing_if = "Option::is_none")]
-    name: Option<String>,
+    pub(crate) name: Option<String>,
 
     /// The tool calls generated by the model, such as function calls.
     #[serde(skip_serializing_if = "Option::is_none")]
-    tool_calls: Option<Vec<ToolCall>>,
+    pub(crate) tool_calls: Option<Vec<ToolCall>>,
 
     /// Tool call that this message is responding to.
     #[serde(skip_serializing_if = "Option::is_none")]
-    tool_call_id: Option<String>,
+    pub(crate) tool_call_id: Option<String>,
 }
 
-#[derive(Deserialize, Debug)]
-struct Usage {
-    prompt_tokens: i64,
-    completion_tokens: i64,
-    total_tokens: i64,
+impl Data {
+    pub(crate) fn new(role: Role, content: &str) -> Self {
+        Data {
+            role: Some(role.to_string()),
+            content: Some(content.to_string()),
+            name: None,
+            tool_calls: None,
+            tool_call_id: None,
+        }
+    }
+}
+
+/// Set, or replace, the system message leading the history.
+pub(crate) fn set_system(history: &mut Vec<Data>, prompt: &str) {
+    let message = Data::new(Role::System, prompt);
+
+    // There is only one system message, and it always leads the conversation
+    match history.first_mut() {
+        Some(first) if first.role.as_deref() == Some("system") => *first = message,
+        _ => history.insert(0, message),
+    }
+}
+
+/// The system message leading the history, if any.
+pub(crate) fn system(history: &[Data]) -> Option<&str> {
+    history
+        .first()
+        .filter(|first| first.role.as_deref() == Some("system"))
+        .and_then(|first| first.content.as_deref())
 }
 
 #[derive(Deserialize, Debug)]
@@ -114,14 +185,31 @@ struct Token {
 struct ErrorObject {
     message: String,
 
-    #[serde(rename = "type")]
-    type_: String,
+    /// Some compatible servers leave out the type.
+    #[serde(rename = "type", default)]
+    type_: Option<String>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     param: Option<String>,
 
+    /// A string for OpenAI, the HTTP status for some compatible servers.
     #[serde(skip_serializing_if = "Option::is_none")]
-    code: Option<String>,
+    code: Option<serde_json::Value>,
+}
+
+impl From<ErrorObject> for Details {
+    fn from(error: ErrorObject) -> Self {
+        Details {
+            message: error.message,
+            type_: error.type_,
+            code: error.code.map(|code| match code {
+                serde_json::Value::String(code) => code,
+                code => code.to_string(),
+            }),
+            param: error.param,
+            ..Default::default()
+        }
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -131,9 +219,16 @@ enum Response {
         error: ErrorObject,
     },
     Completion {
+        #[serde(default)]
         id: String,
+
+        #[serde(default)]
         object: String,
+
+        #[serde(default)]
         created: i64,
+
+        #[serde(default)]
         choices: Vec<Choice>,
 
         #[serde(skip_serializing_if = "Option::is_none")]
@@ -156,6 +251,96 @@ enum Response {
     },
 }
 
+/// What an event of the stream brings.
+#[derive(Debug, PartialEq)]
+enum Chunk {
+    /// Text to add to the reply.
+    Text(String),
+
+    /// Nothing to show, e.g. keep-alives, usage-only chunks or tool calls.
+    Skip,
+
+    /// The `[DONE]` sentinel, the reply is complete.
+    Done,
+}
+
+/// Accumulates the events of a streamed reply. Providers differ in what they
+/// send along the way, e.g. Azure starts with a chunk without choices, and
+/// the usage may come in a final chunk of its own, so anything but the
+/// content is optional.
+#[derive(Debug, Default)]
+struct Decoder {
+    text: String,
+
+    /// The finish reason may come along with the last chunk, or on its own,
+    /// it is reported once the reply is complete.
+    finish_reason: Option<String>,
+    calls: Vec<ToolCall>,
+}
+
+impl Decoder {
+    /// Decode the data of a server-sent event, adding to the metadata of the
+    /// reply as it comes.
+    fn feed(&mut self, data: &str, metadata: &mut Metadata) -> Result<Chunk> {
+        let data = data.trim();
+        if data.is_empty() {
+            return Ok(Chunk::Skip);
+        }
+        if data == "[DONE]" {
+            return Ok(Chunk::Done);
+        }
+
+        let (choices, model, usage, system_fingerprint) =
+            match serde_json::from_str::<Response>(data).map_err(OctoError::protocol)? {
+                Response::Error { error } => {
+                    return Err(OctoError::from(Details::from(error)).into())
+                }
+                Response::Completion {
+                    choices,
+                    model,
+                    usage,
+                    system_fingerprint,
+                    ..
+                } => (choices, model, usage, system_fingerprint),
+            };
+
+        if model.is_some() {
+            metadata.model = model;
+        }
+        if system_fingerprint.is_some() {
+            metadata.system_fingerprint = system_fingerprint;
+        }
+        if let Some(usage) = usage {
+            metadata.add_usage(usage);
+        }
+
+        // only the first choice is shown, the deltas of the others would be
+        // mixed into it when more are requested
+        let Some(choice) = choices
+            .into_iter()
+            .find(|choice| choice.index.unwrap_or(0) == 0)
+        else {
+            return Ok(Chunk::Skip);
+        };
+        if choice.finish_reason.is_some() {
+            self.finish_reason = choice.finish_reason;
+        }
+
+        let Some(reply) = choice.reply else {
+            return Ok(Chunk::Skip);
+        };
+        if let Some(chunks) = &reply.tool_calls {
+            accumulate(&mut self.calls, chunks);
+        }
+
+        let Some(chunk) = reply.content else {
+            return Ok(Chunk::Skip);
+        };
+        self.text.push_str(&chunk);
+        Ok(Chunk::Text(chunk))
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug)]
 struct Tool {
     #[serde(rename = "type")]
@@ -163,10 +348,25 @@ struct Tool {
     function: Function,
 }
 
+#[derive(Deserialize, Serialize, Debug)]
+struct StreamOptions {
+    include_usage: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct JsonSchema {
+    name: String,
+    schema: serde_json::Value,
+}
+
 #[derive(Deserialize, Serialize, Debug)]
 struct ResponseFormat {
     #[serde(rename = "type")]
     type_: String,
+
+    /// Structured outputs, the reply must match the schema.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    json_schema: Option<JsonSchema>,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -241,6 +441,11 @@ struct Request {
     /// server-sent events as they become available, with the stream terminated by a data: [DONE]
     stream: bool,
 
+    /// Options for streaming response, `include_usage` adds a last chunk with the token
+    /// usage of the whole request, and an empty choices list.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<StreamOptions>,
+
     /// What sampling temperature to use, between 0 and 2. Higher values like 0.8 will make
     /// the output more random, while lower values like 0.2 will make it more focused and
     /// deterministic. We generally recommend altering this or top_p but not both.
@@ -286,6 +491,7 @@ impl Default for Request {
             seed: None,
             stop: None,
             stream: false,
+            stream_options: None,
             temperature: 1.0,
             top_p: 1.0,
             user: None,
@@ -295,25 +501,65 @@ impl Default for Request {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
 pub struct Settings {
     pub temperature: f64,
+    pub top_p: f64,
     pub max_tokens: i64,
     pub seed: Option<i64>,
     pub stream: bool,
+    pub frequency_penalty: f64,
+    pub presence_penalty: f64,
+    pub stop: Option<Vec<String>>,
+    pub logit_bias: Option<HashMap<String, f64>>,
+    pub n: i64,
+    pub user: Option<String>,
+
+    /// Ask for a reply made of a single JSON value.
+    pub json: bool,
+
+    /// JSON schema the reply must match, implies `json`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub schema: Option<serde_json::Value>,
+
+    /// How failed requests are tried again, set by octo rather than saved
+    /// with the session.
+    #[serde(skip)]
+    pub retry: Retry,
 }
 
 impl Default for Settings {
     fn default() -> Self {
         Settings {
             temperature: 1.0,
+            top_p: 1.0,
             max_tokens: 1024,
             seed: None,
             stream: false,
+            frequency_penalty: 0.0,
+            presence_penalty: 0.0,
+            stop: None,
+            logit_bias: None,
+            n: 1,
+            user: None,
+            json: false,
+            schema: None,
+            retry: Retry::default(),
         }
     }
 }
 
+/// Map the finish reason onto the conversation state.
+fn finish(reason: &str) -> State<'static> {
+    match reason {
+        "length" => State::OutOfCharacters,
+        "content_filter" => State::ContentFilter,
+        "tool_calls" | "function_call" => State::ToolCalls,
+        _ => State::Stop,
+    }
+}
+
 pub struct Chat {
     client: Client,
     api_key: String,
@@ -321,133 +567,336 @@ pub struct Chat {
     model: String,
     settings: Settings,
     history: Vec<Data>,
+
+    /// How the API key is sent.
+    auth: Auth,
+
+    /// Request fields the provider expects under a different name,
+    /// e.g. Mistral wants `random_seed` rather than `seed`.
+    renames: Vec<(&'static str, &'static str)>,
+
+    /// Functions the model may call.
+    tools: Tools,
+
+    /// Whether to ask for the usage at the end of streams.
+    stream_usage: bool,
+
+    metadata: Metadata,
 }
 
 impl Chat {
-    pub fn new(api_key: &str, url: &str, model: &str, settings: &Settings) -> Self {
+    pub fn new(api_key: &str, url: &str, model: &str, settings: &Settings) -> Result<Self> {
         let client = Client::new();
-        Chat {
+        Ok(Chat {
             client,
             api_key: api_key.to_string(),
-            url: url.parse().unwrap(),
+            url: url.parse().map_err(|e| anyhow!("Invalid URL {url}: {e}"))?,
             model: model.to_string(),
             settings: settings.clone(),
             history: vec![],
-        }
+            auth: Auth::Bearer,
+            renames: vec![],
+            tools: Tools::default(),
+            stream_usage: false,
+            metadata: Metadata::default(),
+        })
     }
-}
 
-#[async_trait]
-impl Conversation for Chat {
-    fn build(&mut self, role: Role, message: &str) -> &mut Self {
-        self.history.push(Data {
-            role: Some(role.to_string()),
-            content: Some(message.to_string()),
-            name: None,
-            tool_calls: None,
-            tool_call_id: None,
-        });
+    pub fn auth(mut self, auth: Auth) -> Self {
+        self.auth = auth;
+        self
+    }
 
+    /// Ask for the token usage with `stream_options`, which not every
+    /// vendor accepts.
+    pub fn stream_usage(mut self) -> Self {
+        self.stream_usage = true;
         self
     }
 
-    async fn send<F>(&mut self, f: F) -> Result<()>
-    where
-        F: Fn(State) + Send,
-    {
-        // FIXME - We may pass all request fields by ref, instead of copying
-        // all values, as this object will be serialized and sent through
-        // network anyway, therefore, allocating all this memory just to drop
-        // it at the end of this scope doesn't sound smart.
-        let request = Request {
+    /// Serialize `field` as `name` in outgoing requests.
+    pub fn rename(mut self, field: &'static str, name: &'static str) -> Self {
+        self.renames.push((field, name));
+        self
+    }
+
+    fn request(&self) -> Request {
+        Request {
             messages: self.history.clone(),
             model: self.model.clone(),
+            frequency_penalty: self.settings.frequency_penalty,
+            logit_bias: self.settings.logit_bias.clone(),
+            max_tokens: self.settings.max_tokens,
+            n: self.settings.n,
+            presence_penalty: self.settings.presence_penalty,
+            seed: self.settings.seed,
+            stop: self.settings.stop.clone(),
             stream: true,
+            stream_options: self.stream_usage.then_some(StreamOptions {
+                include_usage: true,
+            }),
+            temperature: self.settings.temperature,
+            top_p: self.settings.top_p,
+            user: self.settings.user.clone(),
+            response_format: match &self.settings.schema {
+                Some(schema) => Some(ResponseFormat {
+                    type_: "json_schema".to_string(),
+                    json_schema: Some(JsonSchema {
+                        name: "reply".to_string(),
+                        schema: schema.clone(),
+                    }),
+                }),
+                None => self.settings.json.then(|| ResponseFormat {
+                    type_: "json_object".to_string(),
+                    json_schema: None,
+                }),
+            },
+            tools: (!self.tools.is_empty()).then(|| {
+                self.tools
+                    .iter()
+                    .map(|tool| Tool {
+                        type_: "function".to_string(),
+                        function: Function {
+                            name: tool.name.clone(),
+                            description: Some(tool.description.clone()),
+                            parameters: Some(tool.parameters.clone()),
+                            ..Default::default()
+                        },
+                    })
+                    .collect()
+            }),
             ..Default::default()
-        };
+        }
+    }
+
+    /// Send the history and stream the reply, returning the tool calls
+    /// the model asked for, if any.
+    async fn complete(&mut self, f: Callback<'_>) -> Result<Vec<ToolCall>> {
+        // FIXME - We may pass all request fields by ref, instead of copying
+        // all values, as this object will be serialized and sent through
+        // network anyway, therefore, allocating all this memory just to drop
+        // it at the end of this scope doesn't sound smart.
+        let request = self.payload()?;
 
         // Make POST request
         let builder = self
             .client
             .post(self.url.clone())
             .header("Content-Type", "application/json")
-            .header("Authorization", format!("Bearer {}", self.api_key))
             .json(&request);
 
+        let builder = self.auth.apply(builder, &self.api_key);
+
         // We always request stream responses in order to keep the implementation
         // short, regardless of what the users asks. Though, the user can ask for
         // single message response, hence, we build a string out of chunk and return
         // that once the service is done replying.
-        let mut text = String::new();
+        let mut decoder = Decoder::default();
 
-        let mut es = EventSource::new(builder).unwrap();
+        let mut es = EventSource::new(builder)?;
         while let Some(event) = es.next().await {
             match event {
                 Ok(Event::Open) => {
                     f(State::Start);
                 }
                 Ok(Event::Message(message)) => {
-                    //println!("Message: {:#?}", message);
-                    let data_str = message.data.as_str();
-                    if data_str.contains("[DONE]") {
-                        let msg = text.clone();
-
-                        // When we are done, send the text to the user, if stream is false
-                        if !self.settings.stream {
-                            f(State::Message(&msg));
+                    match decoder.feed(&message.data, &mut self.metadata) {
+                        Ok(Chunk::Text(chunk)) => {
+                            // Only send message chunks if the user requested stream
+                            if self.settings.stream {
+                                f(State::Message(&chunk));
+                            }
                         }
-
-                        // Add response to the history
-                        self.history.push(Data {
-                            role: Some(Role::Assistant.to_string()),
-                            content: Some(msg),
-                            name: None,
-                            tool_calls: None,
-                            tool_call_id: None,
-                        });
-
-                        f(State::Done);
-                        es.close();
-                        return Ok(());
-                    } else {
-                        match serde_json::from_str::<Response>(data_str)? {
-                            Response::Error { error } => {
-                                //eprintln!("Error caught: {}", error.message);
-                                es.close();
-                                return Err(anyhow!(error.message));
+                        Ok(Chunk::Skip) => {}
+                        Ok(Chunk::Done) => {
+                            let Decoder {
+                                text: msg,
+                                finish_reason,
+                                mut calls,
+                            } = decoder;
+
+                            // When we are done, send the text to the user, if stream is false
+                            if !self.settings.stream && !msg.is_empty() {
+                                f(State::Message(&msg));
                             }
-                            Response::Completion { choices, .. } => {
-                                let choice = &choices[0];
-
-                                let reply = choice.reply.as_ref().unwrap();
 
-                                if reply.content.is_some() {
-                                    let chunk = reply.content.as_ref().unwrap();
-
-                                    // println!("{}", content.unwrap());
-                                    text.add_assign(chunk);
+                            if let Some(reason) = &finish_reason {
+                                f(finish(reason));
+                            }
+                            self.metadata.finish_reason = finish_reason;
 
-                                    // Only send message chunks if the user requested stream
-                                    if self.settings.stream {
-                                        f(State::Message(chunk));
-                                    }
-                                } else if choice.finish_reason.is_some() {
-                                    // FIXME - Interpret finish_reason
-                                    f(State::Stop);
-                                }
+                            // Add response to the history, along with the tool calls
+                            for call in calls.iter_mut() {
+                                call.index = None;
                             }
+                            self.history.push(Data {
+                                content: (!msg.is_empty() || calls.is_empty()).then_some(msg),
+                                tool_calls: (!calls.is_empty()).then(|| calls.clone()),
+                                ..Data::new(Role::Assistant, "")
+                            });
+
+                            es.close();
+                            return Ok(calls);
                         }
+                        Err(error) => {
+                            es.close();
+                            return Err(error);
+                        }
+                    }
+                }
+                Err(reqwest_eventsource::Error::InvalidStatusCode(status, response)) => {
+                    es.close();
+                    let headers = response.headers().clone();
+                    let body = response.text().await.unwrap_or_default();
+                    let mut details = Details::response(status, &headers, body);
+                    if let Some(Response::Error { error }) = details
+                        .body
+                        .as_deref()
+                        .and_then(|body| serde_json::from_str(body).ok())
+                    {
+                        details = details.with(error.into());
                     }
+                    return Err(OctoError::from(details).into());
+                }
+                Err(reqwest_eventsource::Error::StreamEnded) => {
+                    es.close();
+                    return Err(OctoError::ended().into());
                 }
                 Err(error) => {
                     //eprintln!("Error caught: {}", error);
                     es.close();
-                    return Err(anyhow!(error.to_string()));
+                    return Err(OctoError::network(error).into());
+                }
+            }
+        }
+
+        Err(OctoError::ended().into())
+    }
+
+    /// Build the JSON body to send, with provider-specific field names applied.
+    fn payload(&self) -> Result<serde_json::Value> {
+        let mut payload = serde_json::to_value(self.request())?;
+
+        if let Some(fields) = payload.as_object_mut() {
+            for (field, name) in &self.renames {
+                if let Some(value) = fields.remove(*field) {
+                    fields.insert(name.to_string(), value);
+                }
+            }
+        }
+
+        Ok(payload)
+    }
+}
+
+#[async_trait]
+impl Conversation for Chat {
+    fn build(&mut self, role: Role, message: &str) -> &mut dyn Conversation {
+        self.history.push(Data::new(role, message));
+        self
+    }
+
+    fn set_system(&mut self, prompt: &str) -> &mut dyn Conversation {
+        set_system(&mut self.history, prompt);
+        self
+    }
+
+    fn system(&self) -> Option<&str> {
+        system(&self.history)
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn set_model(&mut self, model: &str) {
+        self.model = model.to_string();
+    }
+
+    fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    fn settings_mut(&mut self) -> &mut Settings {
+        &mut self.settings
+    }
+
+    fn history(&self) -> &[Data] {
+        &self.history
+    }
+
+    fn restore(&mut self, history: Vec<Data>) {
+        self.history = history;
+    }
+
+    fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    fn set_tools(&mut self, tools: Tools) -> Result<()> {
+        self.tools = tools;
+        Ok(())
+    }
+
+    fn take_tools(&mut self) -> Tools {
+        std::mem::take(&mut self.tools)
+    }
+
+    async fn models(&self) -> Result<Vec<String>> {
+        #[derive(Deserialize)]
+        struct Model {
+            id: String,
+        }
+
+        #[derive(Deserialize)]
+        struct Models {
+            data: Vec<Model>,
+        }
+
+        // Models are listed next to the chat completions endpoint
+        let url = self.url.join("../models")?;
+        let builder = self.client.get(url);
+        let builder = self.auth.apply(builder, &self.api_key);
+
+        let models = builder
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Models>()
+            .await?;
+        Ok(models.data.into_iter().map(|model| model.id).collect())
+    }
+
+    async fn send(&mut self, f: Callback<'_>) -> Result<()> {
+        self.metadata = Metadata::default();
+
+        // Keep answering tool calls until the model replies with a message
+        for _ in 0..=self.tools.max_iterations {
+            let calls = self.complete(f).await?;
+            if calls.is_empty() {
+                if let Some(usage) = self.metadata.usage {
+                    f(State::Usage(usage));
                 }
+                f(State::Done);
+                return Ok(());
+            }
+
+            for call in calls {
+                let arguments = call.function.arguments.unwrap_or_default();
+                f(State::Tool(&call.function.name, &arguments));
+
+                let result = self.tools.call(&call.function.name, &arguments).await;
+                self.history.push(Data {
+                    tool_call_id: Some(call.id),
+                    ..Data::new(Role::Tool, &result)
+                });
             }
         }
 
-        return Ok(());
+        Err(anyhow!(
+            "No final answer after {} rounds of tool calls",
+            self.tools.max_iterations
+        ))
     }
 }
 
@@ -455,33 +904,473 @@ impl Conversation for Chat {
 mod tests {
     use super::*;
 
+    use proptest::prelude::*;
+
+    /// Streams in the shape sent by OpenAI and compatible providers, written
+    /// by hand rather than recorded, see `tests/fixtures/README.md`.
+    const CORPUS: &[&str] = &[
+        include_str!("../tests/fixtures/openai/answer.sse"),
+        include_str!("../tests/fixtures/openai/azure.sse"),
+        include_str!("../tests/fixtures/openai/json.sse"),
+        include_str!("../tests/fixtures/openai/keepalive.sse"),
+        include_str!("../tests/fixtures/openai/length.sse"),
+        include_str!("../tests/fixtures/openai/tool_calls.sse"),
+        include_str!("../tests/fixtures/openai/usage.sse"),
+    ];
+
+    /// The data of each event of a stream, leaving out comments.
+    fn events(stream: &str) -> Vec<String> {
+        stream
+            .split("\n\n")
+            .flat_map(|event| event.split("\r\n\r\n"))
+            .filter_map(|event| {
+                let data = event
+                    .lines()
+                    .filter_map(|line| line.strip_prefix("data:"))
+                    .map(|data| data.strip_prefix(' ').unwrap_or(data))
+                    .collect::<Vec<_>>();
+                (!data.is_empty()).then(|| data.join("\n"))
+            })
+            .collect()
+    }
+
     #[tokio::test]
     async fn test_chat_request() {
         vec![
-            Data {
-                role: Some(Role::User.to_string()),
-                content: Some("Hello".to_string()),
-                name: None,
-                tool_calls: None,
-                tool_call_id: None,
-            },
-            Data {
-                role: Some(Role::User.to_string()),
-                content: Some("How are you?".to_string()),
-                name: None,
-                tool_calls: None,
-                tool_call_id: None,
-            },
+            Data::new(Role::User, "Hello"),
+            Data::new(Role::User, "How are you?"),
         ];
         let model = "gpt-3.5-turbo";
         let url = "https://api.openai.com/v1/engines/davinci-codex/completions";
         let api_key = "YOUR_API_KEY";
         let settings = Settings::default();
 
-        let mut chat = Chat::new(api_key, url, model, &settings);
+        let mut chat = Chat::new(api_key, url, model, &settings).unwrap();
 
-        let result = chat.send(|_| {}).await;
+        let result = chat.send(&|_| {}).await;
 
         assert!(result.is_err(), "{result:?}");
     }
+
+    #[test]
+    fn test_request_settings() {
+        let settings = Settings {
+            temperature: 0.2,
+            top_p: 0.9,
+            max_tokens: 64,
+            seed: Some(42),
+            stream: false,
+            frequency_penalty: 0.5,
+            presence_penalty: -0.5,
+            stop: Some(vec!["\n\n".to_string()]),
+            logit_bias: Some(HashMap::from([("50256".to_string(), -100.0)])),
+            n: 2,
+            user: Some("octo".to_string()),
+            json: true,
+            schema: None,
+            retry: Retry::default(),
+        };
+
+        let mut chat = Chat::new("key", "http://localhost", "model", &settings).unwrap();
+        chat.build(Role::User, "Hello");
+
+        let payload = chat.payload().unwrap();
+        assert_eq!(payload["model"], "model");
+        assert_eq!(payload["messages"][0]["content"], "Hello");
+        assert_eq!(payload["temperature"], 0.2);
+        assert_eq!(payload["top_p"], 0.9);
+        assert_eq!(payload["max_tokens"], 64);
+        assert_eq!(payload["seed"], 42);
+        assert_eq!(payload["frequency_penalty"], 0.5);
+        assert_eq!(payload["presence_penalty"], -0.5);
+        assert_eq!(payload["stop"][0], "\n\n");
+        assert_eq!(payload["logit_bias"]["50256"], -100.0);
+        assert_eq!(payload["n"], 2);
+        assert_eq!(payload["user"], "octo");
+        assert_eq!(payload["stream"], true);
+        assert_eq!(payload["response_format"]["type"], "json_object");
+
+        chat.settings_mut().schema = Some(serde_json::json!({ "type": "object" }));
+        let payload = chat.payload().unwrap();
+        assert_eq!(payload["response_format"]["type"], "json_schema");
+        assert_eq!(
+            payload["response_format"]["json_schema"]["schema"]["type"],
+            "object"
+        );
+
+        let chat = chat.rename("seed", "random_seed");
+        let payload = chat.payload().unwrap();
+        assert!(payload.get("seed").is_none());
+        assert_eq!(payload["random_seed"], 42);
+    }
+
+    #[test]
+    fn test_system_prompt() {
+        let mut chat = Chat::new("key", "http://localhost", "model", &Settings::default()).unwrap();
+        assert_eq!(chat.system(), None);
+
+        chat.build(Role::User, "Hello")
+            .build(Role::Assistant, "Hi!")
+            .set_system("Be brief");
+        assert_eq!(chat.system(), Some("Be brief"));
+        assert_eq!(chat.history.len(), 3);
+
+        chat.set_system("Be verbose");
+        assert_eq!(chat.system(), Some("Be verbose"));
+        assert_eq!(chat.history.len(), 3);
+        assert_eq!(chat.history[1].content.as_deref(), Some("Hello"));
+        assert_eq!(chat.history[2].content.as_deref(), Some("Hi!"));
+    }
+
+    #[tokio::test]
+    async fn test_finish_reason() {
+        use std::sync::Mutex;
+        use wiremock::{matchers::method, Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(
+                include_str!("../tests/fixtures/openai/length.sse"),
+                "text/event-stream",
+            ))
+            .mount(&server)
+            .await;
+
+        for (stream, expected) in [
+            (
+                true,
+                vec![
+                    "start",
+                    "message: ",
+                    "message: Once upon",
+                    "message:  a time",
+                    "length",
+                    "done",
+                ],
+            ),
+            (
+                false,
+                vec!["start", "message: Once upon a time", "length", "done"],
+            ),
+        ] {
+            let settings = Settings {
+                stream,
+                ..Default::default()
+            };
+            let mut chat = Chat::new("key", &server.uri(), "model", &settings).unwrap();
+
+            let states = Mutex::new(vec![]);
+            chat.build(Role::User, "Tell me a story")
+                .send(&|state| states.lock().unwrap().push(state.to_string()))
+                .await
+                .unwrap();
+
+            assert_eq!(*states.lock().unwrap(), expected);
+
+            let metadata = chat.metadata();
+            assert_eq!(metadata.model.as_deref(), Some("gpt-3.5-turbo-1106"));
+            assert_eq!(metadata.system_fingerprint.as_deref(), Some("fp_1"));
+            assert_eq!(metadata.finish_reason.as_deref(), Some("length"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stream_usage() {
+        use std::sync::Mutex;
+        use wiremock::{
+            matchers::{body_partial_json, method},
+            Mock, MockServer, ResponseTemplate,
+        };
+
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(body_partial_json(serde_json::json!({
+                "stream_options": { "include_usage": true }
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(
+                include_str!("../tests/fixtures/openai/usage.sse"),
+                "text/event-stream",
+            ))
+            .mount(&server)
+            .await;
+
+        let mut chat = Chat::new("key", &server.uri(), "gpt-4o", &Settings::default())
+            .unwrap()
+            .stream_usage();
+        let states = Mutex::new(vec![]);
+        chat.build(Role::User, "Hello")
+            .send(&|state| states.lock().unwrap().push(state.to_string()))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            *states.lock().unwrap(),
+            [
+                "start",
+                "message: Hi!",
+                "stop",
+                "usage: 9 + 2 tokens",
+                "done"
+            ]
+        );
+        assert_eq!(chat.metadata().usage.unwrap().total_tokens, 11);
+    }
+
+    #[tokio::test]
+    async fn test_error_body() {
+        use crate::error::OctoError;
+        use wiremock::{matchers::method, Mock, MockServer, ResponseTemplate};
+
+        let body = include_str!("../tests/fixtures/openai/context_length.json");
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(400).set_body_raw(body, "application/json"))
+            .mount(&server)
+            .await;
+
+        let mut chat = Chat::new("key", &server.uri(), "gpt-4", &Settings::default()).unwrap();
+        let error = chat
+            .build(Role::User, "Hello")
+            .send(&|_| {})
+            .await
+            .unwrap_err();
+
+        let Some(OctoError::ContextLength(details)) = error.downcast_ref() else {
+            panic!("{error:?}");
+        };
+        assert_eq!(details.param.as_deref(), Some("messages"));
+        assert_eq!(details.body.as_deref(), Some(body));
+        assert!(error.to_string().ends_with(
+            "(400 Bad Request, invalid_request_error, context_length_exceeded, messages)"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_tool_calls() {
+        use serde_json::json;
+        use std::sync::Mutex;
+        use wiremock::{
+            matchers::{body_partial_json, body_string_contains, method},
+            Mock, MockServer, ResponseTemplate,
+        };
+
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(body_partial_json(json!({
+                "tools": [{ "type": "function", "function": { "name": "add" } }]
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(
+                include_str!("../tests/fixtures/openai/tool_calls.sse"),
+                "text/event-stream",
+            ))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(body_string_contains(r#""tool_call_id":"call_1""#))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(
+                include_str!("../tests/fixtures/openai/answer.sse"),
+                "text/event-stream",
+            ))
+            .mount(&server)
+            .await;
+
+        let mut tools = Tools::default();
+        tools.register(
+            "add",
+            "Add two numbers",
+            json!({ "type": "object" }),
+            |arguments: serde_json::Value| {
+                Ok(
+                    (arguments["a"].as_i64().unwrap() + arguments["b"].as_i64().unwrap())
+                        .to_string(),
+                )
+            },
+        );
+
+        let settings = Settings {
+            stream: true,
+            ..Default::default()
+        };
+        let mut chat = Chat::new("key", &server.uri(), "model", &settings).unwrap();
+        chat.set_tools(tools).unwrap();
+
+        let states = Mutex::new(vec![]);
+        chat.build(Role::User, "What is 1 + 2?")
+            .send(&|state| states.lock().unwrap().push(state.to_string()))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            *states.lock().unwrap(),
+            [
+                "start",
+                "tool_calls",
+                r#"tool: add {"a": 1, "b": 2}"#,
+                "start",
+                "message: It is 3",
+                "stop",
+                "done"
+            ]
+        );
+
+        let roles = chat
+            .history()
+            .iter()
+            .map(|data| data.role.as_deref().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(roles, ["user", "assistant", "tool", "assistant"]);
+        assert_eq!(chat.history()[2].content.as_deref(), Some("3"));
+    }
+
+    #[test]
+    fn test_decode_corpus() {
+        for stream in CORPUS {
+            let (mut decoder, mut metadata) = (Decoder::default(), Metadata::default());
+            let chunks = events(stream)
+                .iter()
+                .map(|data| decoder.feed(data, &mut metadata).unwrap())
+                .collect::<Vec<_>>();
+            assert_eq!(chunks.last(), Some(&Chunk::Done), "{stream}");
+        }
+
+        // Azure starts without choices, and the text may mention [DONE]
+        let (mut decoder, mut metadata) = (Decoder::default(), Metadata::default());
+        for data in events(CORPUS[1]) {
+            decoder.feed(&data, &mut metadata).unwrap();
+        }
+        assert_eq!(decoder.text, "Reply with [DONE] when finished.");
+        assert_eq!(decoder.finish_reason.as_deref(), Some("stop"));
+        assert_eq!(metadata.model.as_deref(), Some("gpt-4o-2024-05-13"));
+        assert_eq!(metadata.usage.unwrap().total_tokens, 19);
+    }
+
+    #[test]
+    fn test_decode_choices() {
+        // with n > 1 the deltas of the choices come interleaved
+        let (mut decoder, mut metadata) = (Decoder::default(), Metadata::default());
+        for (index, content, finish_reason) in [
+            (0, "Hello", None),
+            (1, "Bonjour", None),
+            (1, " le monde", Some("stop")),
+            (0, " world", Some("length")),
+        ] {
+            let data = serde_json::json!({
+                "choices": [{
+                    "index": index,
+                    "delta": { "content": content },
+                    "finish_reason": finish_reason,
+                }]
+            });
+            decoder.feed(&data.to_string(), &mut metadata).unwrap();
+        }
+        assert_eq!(decoder.text, "Hello world");
+        assert_eq!(decoder.finish_reason.as_deref(), Some("length"));
+    }
+
+    #[tokio::test]
+    async fn test_stream_quirks() {
+        use std::sync::Mutex;
+        use wiremock::{matchers::method, Mock, MockServer, ResponseTemplate};
+
+        for (stream, text) in [
+            (CORPUS[1], "Reply with [DONE] when finished."),
+            (CORPUS[3], "Hello there"),
+        ] {
+            let server = MockServer::start().await;
+            Mock::given(method("POST"))
+                .respond_with(ResponseTemplate::new(200).set_body_raw(stream, "text/event-stream"))
+                .mount(&server)
+                .await;
+
+            let settings = Settings {
+                stream: false,
+                ..Default::default()
+            };
+            let mut chat = Chat::new("key", &server.uri(), "model", &settings).unwrap();
+            let states = Mutex::new(vec![]);
+            chat.build(Role::User, "Hi")
+                .send(&|state| states.lock().unwrap().push(state.to_string()))
+                .await
+                .unwrap();
+
+            let states = states.into_inner().unwrap();
+            assert_eq!(states[1], format!("message: {text}"));
+            assert_eq!(states.last().map(String::as_str), Some("done"));
+        }
+    }
+
+    #[test]
+    fn test_repeated_tool_names() {
+        let (mut decoder, mut metadata) = (Decoder::default(), Metadata::default());
+        for arguments in ["{\"a\":", " 1}"] {
+            let data = serde_json::json!({
+                "choices": [{
+                    "delta": {
+                        "tool_calls": [{
+                            "index": 0,
+                            "function": { "name": "add", "arguments": arguments }
+                        }]
+                    }
+                }]
+            });
+            decoder.feed(&data.to_string(), &mut metadata).unwrap();
+        }
+        assert_eq!(decoder.calls[0].function.name, "add");
+        assert_eq!(
+            decoder.calls[0].function.arguments.as_deref(),
+            Some("{\"a\": 1}")
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn test_decode_any_data(data in any::<String>()) {
+            let _ = Decoder::default().feed(&data, &mut Metadata::default());
+        }
+
+        /// Corrupting an event of a recorded stream makes it fail, without
+        /// panicking, with a typed error.
+        #[test]
+        fn test_decode_corrupted(
+            stream in 0..CORPUS.len(),
+            event in any::<prop::sample::Index>(),
+            cut in any::<prop::sample::Index>(),
+            junk in "\\PC{0,8}",
+        ) {
+            let mut events = events(CORPUS[stream]);
+            let index = event.index(events.len());
+            let data = &mut events[index];
+            let mut end = cut.index(data.len() + 1);
+            while !data.is_char_boundary(end) {
+                end -= 1;
+            }
+            data.truncate(end);
+            data.push_str(&junk);
+
+            let (mut decoder, mut metadata) = (Decoder::default(), Metadata::default());
+            for data in &events {
+                if let Err(error) = decoder.feed(data, &mut metadata) {
+                    prop_assert!(error.downcast_ref::<OctoError>().is_some());
+                }
+            }
+        }
+
+        /// Tool call indexes never make the calls grow past one per chunk.
+        #[test]
+        fn test_tool_call_indexes(indexes in prop::collection::vec(any::<usize>(), 0..16)) {
+            let (mut decoder, mut metadata) = (Decoder::default(), Metadata::default());
+            for index in &indexes {
+                let data = serde_json::json!({
+                    "choices": [{
+                        "delta": {
+                            "tool_calls": [{ "index": index, "function": { "name": "f" } }]
+                        }
+                    }]
+                });
+                decoder.feed(&data.to_string(), &mut metadata).unwrap();
+            }
+            prop_assert!(decoder.calls.len() <= indexes.len());
+        }
+    }
 }
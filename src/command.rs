@@ -0,0 +1,273 @@
+use std::{fmt::Write, path::PathBuf};
+
+use anyhow::{anyhow, Result};
+
+/// REPL commands, anything not starting with `/` is a user message.
+#[derive(Debug, PartialEq)]
+pub enum Cmd {
+    Exit,
+    Help,
+    User(String),
+    Continue,
+    System(Option<String>),
+    Context(ContextCmd),
+    Models,
+    Json(Option<bool>),
+    Raw(Option<bool>),
+    Profile(Option<String>),
+    Usage,
+    Save(Option<PathBuf>),
+    Load(PathBuf),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ContextCmd {
+    Add(Vec<String>),
+    List,
+    Drop(PathBuf),
+    Resources,
+}
+
+/// Describes a command, used to parse the input and generate `/help`.
+pub struct Spec {
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    pub args: &'static str,
+    pub help: &'static str,
+}
+
+pub const COMMANDS: &[Spec] = &[
+    Spec {
+        name: "help",
+        aliases: &["?"],
+        args: "",
+        help: "Show this help",
+    },
+    Spec {
+        name: "exit",
+        aliases: &["quit"],
+        args: "",
+        help: "Exit the program",
+    },
+    Spec {
+        name: "continue",
+        aliases: &[],
+        args: "",
+        help: "Ask the model to keep going after a truncated reply",
+    },
+    Spec {
+        name: "system",
+        aliases: &[],
+        args: "[prompt]",
+        help: "Provide the conversation with a system prompt",
+    },
+    Spec {
+        name: "context",
+        aliases: &[],
+        args: "@file1 @./dir/* @scheme://uri | list | drop <path> | resources",
+        help: "Attach files, directories, globs or MCP resources to the next message",
+    },
+    Spec {
+        name: "models",
+        aliases: &[],
+        args: "",
+        help: "List the models available from the provider",
+    },
+    Spec {
+        name: "json",
+        aliases: &[],
+        args: "[on|off]",
+        help: "Ask for JSON replies, without argument show whether it is on",
+    },
+    Spec {
+        name: "raw",
+        aliases: &[],
+        args: "[on|off]",
+        help: "Print replies without rendering Markdown, without argument toggle it",
+    },
+    Spec {
+        name: "profile",
+        aliases: &[],
+        args: "[name]",
+        help: "Switch to a profile of the configuration, without argument list them",
+    },
+    Spec {
+        name: "usage",
+        aliases: &[],
+        args: "",
+        help: "Show the tokens used so far and their estimated cost",
+    },
+    Spec {
+        name: "save",
+        aliases: &[],
+        args: "[file]",
+        help: "Save conversation to a JSON file, defaults to the --session one",
+    },
+    Spec {
+        name: "load",
+        aliases: &[],
+        args: "<file>",
+        help: "Load conversation from a JSON file",
+    },
+];
+
+impl Cmd {
+    pub fn parse(input: &str) -> Result<Cmd> {
+        let Some(line) = input.strip_prefix('/') else {
+            return Ok(Cmd::User(input.to_string()));
+        };
+
+        let (word, args) = split(line);
+
+        let spec = COMMANDS
+            .iter()
+            .find(|spec| spec.name == word || spec.aliases.contains(&word))
+            .ok_or(anyhow!("Unknown command /{word}, type /help for a list"))?;
+
+        let cmd = match spec.name {
+            "help" => Cmd::Help,
+            "exit" => Cmd::Exit,
+            "continue" => Cmd::Continue,
+            "system" => Cmd::System((!args.is_empty()).then(|| args.to_string())),
+            "context" => Cmd::Context(match split(args) {
+                ("list", "") => ContextCmd::List,
+                ("resources", "") => ContextCmd::Resources,
+                ("drop", path) => ContextCmd::Drop(path_arg(spec, path)?),
+                ("" | "list" | "resources", _) => {
+                    return Err(anyhow!("Usage: /{} {}", spec.name, spec.args))
+                }
+                _ => ContextCmd::Add(args.split_whitespace().map(str::to_string).collect()),
+            }),
+            "models" => Cmd::Models,
+            "json" => Cmd::Json(match args {
+                "" => None,
+                "on" => Some(true),
+                "off" => Some(false),
+                _ => return Err(anyhow!("Usage: /{} {}", spec.name, spec.args)),
+            }),
+            "raw" => Cmd::Raw(match args {
+                "" => None,
+                "on" => Some(true),
+                "off" => Some(false),
+                _ => return Err(anyhow!("Usage: /{} {}", spec.name, spec.args)),
+            }),
+            "profile" => Cmd::Profile((!args.is_empty()).then(|| args.to_string())),
+            "usage" => Cmd::Usage,
+            "save" => Cmd::Save((!args.is_empty()).then(|| PathBuf::from(args))),
+            "load" => Cmd::Load(path_arg(spec, args)?),
+            _ => unreachable!("command /{} has no parser", spec.name),
+        };
+
+        Ok(cmd)
+    }
+}
+
+/// Split the first word off a line, and trim what follows.
+fn split(line: &str) -> (&str, &str) {
+    line.split_once(char::is_whitespace)
+        .map(|(word, rest)| (word, rest.trim()))
+        .unwrap_or((line, ""))
+}
+
+fn path_arg(spec: &Spec, args: &str) -> Result<PathBuf> {
+    if args.is_empty() {
+        return Err(anyhow!("Usage: /{} {}", spec.name, spec.args));
+    }
+    Ok(PathBuf::from(args))
+}
+
+/// Generate the help text from the command table.
+pub fn help() -> String {
+    let usage = |spec: &Spec| format!("/{} {}", spec.name, spec.args);
+    let width = COMMANDS
+        .iter()
+        .map(|spec| usage(spec).len())
+        .max()
+        .unwrap_or(0);
+
+    let mut text = String::new();
+    for spec in COMMANDS {
+        let aliases = spec
+            .aliases
+            .iter()
+            .map(|alias| format!("/{alias}"))
+            .collect::<Vec<_>>();
+
+        write!(text, "{:width$}  {}", usage(spec), spec.help).unwrap();
+        if !aliases.is_empty() {
+            write!(text, " (alias {})", aliases.join(", ")).unwrap();
+        }
+        writeln!(text).unwrap();
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_commands() {
+        assert_eq!(Cmd::parse("Hello").unwrap(), Cmd::User("Hello".to_string()));
+        assert_eq!(Cmd::parse("/quit").unwrap(), Cmd::Exit);
+        assert_eq!(Cmd::parse("/system").unwrap(), Cmd::System(None));
+        assert_eq!(
+            Cmd::parse("/system  Be brief ").unwrap(),
+            Cmd::System(Some("Be brief".to_string()))
+        );
+        assert_eq!(
+            Cmd::parse("/context @a.rs @src/b.rs").unwrap(),
+            Cmd::Context(ContextCmd::Add(vec![
+                "@a.rs".to_string(),
+                "@src/b.rs".to_string()
+            ]))
+        );
+        assert_eq!(
+            Cmd::parse("/context list").unwrap(),
+            Cmd::Context(ContextCmd::List)
+        );
+        assert_eq!(
+            Cmd::parse("/context drop src/b.rs").unwrap(),
+            Cmd::Context(ContextCmd::Drop(PathBuf::from("src/b.rs")))
+        );
+        assert_eq!(
+            Cmd::parse("/context resources").unwrap(),
+            Cmd::Context(ContextCmd::Resources)
+        );
+        for line in [
+            "/context list foo",
+            "/context resources x",
+            "/context\tlist  a b",
+        ] {
+            let error = Cmd::parse(line).unwrap_err().to_string();
+            assert!(error.starts_with("Usage: /context"), "{line}: {error}");
+        }
+        assert_eq!(
+            Cmd::parse("/context  drop\tsrc/b.rs ").unwrap(),
+            Cmd::Context(ContextCmd::Drop(PathBuf::from("src/b.rs")))
+        );
+        assert_eq!(Cmd::parse("/json on").unwrap(), Cmd::Json(Some(true)));
+        assert_eq!(Cmd::parse("/json").unwrap(), Cmd::Json(None));
+        assert_eq!(Cmd::parse("/raw off").unwrap(), Cmd::Raw(Some(false)));
+        assert_eq!(
+            Cmd::parse("/profile code").unwrap(),
+            Cmd::Profile(Some("code".to_string()))
+        );
+        assert!(Cmd::parse("/json maybe").is_err());
+        assert_eq!(
+            Cmd::parse("/save ./chat.json").unwrap(),
+            Cmd::Save(Some(PathBuf::from("./chat.json")))
+        );
+        assert_eq!(Cmd::parse("/save").unwrap(), Cmd::Save(None));
+        assert!(Cmd::parse("/load").is_err());
+        assert!(Cmd::parse("/unknown").is_err());
+    }
+
+    #[test]
+    fn test_help_lists_commands() {
+        let help = help();
+        for spec in COMMANDS {
+            assert!(help.contains(&format!("/{}", spec.name)));
+        }
+    }
+}
@@ -0,0 +1,295 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use clap::{parser::ValueSource, ArgMatches};
+use serde::{Deserialize, Serialize};
+
+use crate::context::Budget;
+use crate::window::Policy;
+use crate::Opts;
+
+/// Where octo reads its configuration files from, `~/.config/octo` unless
+/// `XDG_CONFIG_HOME` says otherwise.
+pub fn dir() -> Option<PathBuf> {
+    let config = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    Some(config.join("octo"))
+}
+
+/// Options set by the configuration file, named like the command line ones.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub provider: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<i64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system_file: Option<PathBuf>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tools: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub root: Option<PathBuf>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub json: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub schema: Option<PathBuf>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prices: Option<PathBuf>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub plugins: Option<PathBuf>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mcp: Option<Vec<String>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retries: Option<u32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_partial: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tool_iterations: Option<usize>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context_budget: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context_window: Option<usize>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub truncate: Option<Policy>,
+}
+
+/// Whether an option was given on the command line or in the environment,
+/// rather than left to its default.
+pub(crate) fn given(matches: &ArgMatches, id: &str) -> bool {
+    matches!(
+        matches.value_source(id),
+        Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+    )
+}
+
+/// Set the options of the profile which were not given explicitly.
+macro_rules! apply {
+    ($profile:expr, $opts:expr, $matches:expr, { $($field:ident),* }, { $($optional:ident),* }) => {
+        $(
+            if let (Some(value), false) = (&$profile.$field, given($matches, stringify!($field))) {
+                $opts.$field = value.clone();
+            }
+        )*
+        $(
+            if let (Some(value), false) = (&$profile.$optional, given($matches, stringify!($optional))) {
+                $opts.$optional = Some(value.clone());
+            }
+        )*
+    };
+}
+
+impl Profile {
+    /// Apply the profile to the options, those given on the command line or
+    /// in the environment take precedence.
+    pub fn apply(&self, opts: &mut Opts, matches: &ArgMatches) -> Result<()> {
+        apply!(self, opts, matches, {
+            provider, stream, raw, temperature, max_tokens, top_p, frequency_penalty,
+            presence_penalty, stop, tools, root, json, mcp, retries, retry_partial,
+            max_tool_iterations, truncate
+        }, {
+            url, model, seed, user, schema, prices, plugins, context_window
+        });
+
+        // Either system prompt given explicitly replaces both
+        let explicit = given(matches, "system") || given(matches, "system_file");
+        if !explicit && (self.system.is_some() || self.system_file.is_some()) {
+            opts.system = self.system.clone();
+            opts.system_file = self.system_file.clone();
+        }
+
+        if let (Some(budget), false) = (&self.context_budget, given(matches, "context_budget")) {
+            opts.context_budget = budget
+                .parse()
+                .map_err(|e| anyhow!("Invalid context_budget '{budget}': {e}"))?;
+        }
+        Ok(())
+    }
+}
+
+impl From<&Opts> for Profile {
+    fn from(opts: &Opts) -> Self {
+        Profile {
+            provider: Some(opts.provider.clone()),
+            url: opts.url.clone(),
+            model: opts.model.clone(),
+            stream: Some(opts.stream),
+            raw: Some(opts.raw),
+            temperature: Some(opts.temperature),
+            max_tokens: Some(opts.max_tokens),
+            seed: opts.seed,
+            top_p: Some(opts.top_p),
+            frequency_penalty: Some(opts.frequency_penalty),
+            presence_penalty: Some(opts.presence_penalty),
+            stop: Some(opts.stop.clone()),
+            user: opts.user.clone(),
+            system: opts.system.clone(),
+            system_file: opts.system_file.clone(),
+            tools: Some(opts.tools),
+            root: Some(opts.root.clone()),
+            json: Some(opts.json),
+            schema: opts.schema.clone(),
+            prices: opts.prices.clone(),
+            plugins: opts.plugins.clone(),
+            mcp: Some(opts.mcp.clone()),
+            retries: Some(opts.retries),
+            retry_partial: Some(opts.retry_partial),
+            max_tool_iterations: Some(opts.max_tool_iterations),
+            context_budget: Some(match opts.context_budget {
+                Budget::Bytes(bytes) => bytes.to_string(),
+                Budget::Tokens(tokens) => format!("{tokens}t"),
+            }),
+            context_window: opts.context_window,
+            truncate: Some(opts.truncate),
+        }
+    }
+}
+
+/// The configuration file, with defaults at the top level and named profiles
+/// in `[profile.<name>]` tables.
+#[derive(Deserialize, Debug, Default)]
+pub struct Config {
+    #[serde(default)]
+    pub profile: BTreeMap<String, Profile>,
+
+    #[serde(flatten)]
+    pub defaults: Profile,
+}
+
+impl Config {
+    /// Read the configuration, from `~/.config/octo/config.toml` by default.
+    /// A missing file is only an error if it was asked for explicitly.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let (path, required) = match path {
+            Some(path) => (path.to_path_buf(), true),
+            None => match dir() {
+                Some(dir) => (dir.join("config.toml"), false),
+                None => return Ok(Config::default()),
+            },
+        };
+        if !required && !path.exists() {
+            return Ok(Config::default());
+        }
+
+        let content = fs::read_to_string(&path)
+            .map_err(|e| anyhow!("Cannot read {}: {e}", path.display()))?;
+        toml::from_str(&content).map_err(|e| anyhow!("Invalid {}: {e}", path.display()))
+    }
+
+    pub fn profile(&self, name: &str) -> Result<&Profile> {
+        self.profile.get(name).ok_or_else(|| {
+            let names = self.profile.keys().cloned().collect::<Vec<_>>();
+            match names.is_empty() {
+                true => anyhow!("Unknown profile {name}, none is configured"),
+                false => anyhow!(
+                    "Unknown profile {name}, expected one of {}",
+                    names.join(", ")
+                ),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use clap::{CommandFactory, FromArgMatches};
+
+    #[test]
+    fn test_profiles() {
+        let config: Config = toml::from_str(
+            r#"
+            model = "gpt-4o"
+            temperature = 0.5
+            system = "Be brief"
+
+            [profile.code]
+            provider = "anthropic"
+            temperature = 0.2
+            system_file = "./code.md"
+            context_budget = "8kt"
+            "#,
+        )
+        .unwrap();
+
+        let matches = Opts::command()
+            .try_get_matches_from(["octo", "-r", "100", "--system", "Be kind"])
+            .unwrap();
+        let mut opts = Opts::from_arg_matches(&matches).unwrap();
+        config.defaults.apply(&mut opts, &matches).unwrap();
+        config
+            .profile("code")
+            .unwrap()
+            .apply(&mut opts, &matches)
+            .unwrap();
+
+        assert_eq!(opts.provider, "anthropic");
+        assert_eq!(opts.model.as_deref(), Some("gpt-4o"));
+        assert_eq!(opts.temperature, 0.2);
+        assert_eq!(opts.max_tokens, 100);
+        assert_eq!(opts.system.as_deref(), Some("Be kind"));
+        assert_eq!(opts.system_file, None);
+        assert_eq!(opts.context_budget, Budget::Tokens(8192));
+        assert_eq!(opts.top_p, 1.0);
+
+        assert!(config.profile("chat").is_err());
+        assert!(toml::from_str::<Config>("[profile.code]\nmodle = \"x\"").is_err());
+    }
+}
@@ -0,0 +1,363 @@
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Result};
+
+/// Rough number of bytes per token, good enough to enforce a budget.
+const BYTES_PER_TOKEN: usize = 4;
+
+/// How much file content can be attached to a single user turn.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Budget {
+    Bytes(usize),
+    Tokens(usize),
+}
+
+impl Budget {
+    fn bytes(&self) -> usize {
+        match self {
+            Budget::Bytes(bytes) => *bytes,
+            Budget::Tokens(tokens) => tokens * BYTES_PER_TOKEN,
+        }
+    }
+}
+
+impl FromStr for Budget {
+    type Err = anyhow::Error;
+
+    /// Parse `<N>` or `<N>b` as bytes, `<N>t` as tokens, `k` multiplies by 1024.
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim().to_lowercase();
+        let (number, tokens) = match s.strip_suffix('t') {
+            Some(number) => (number, true),
+            None => (s.strip_suffix('b').unwrap_or(&s), false),
+        };
+        let (number, scale) = match number.strip_suffix('k') {
+            Some(number) => (number, 1024),
+            None => (number, 1),
+        };
+        let value = number
+            .parse::<usize>()
+            .map_err(|_| anyhow!("invalid budget '{s}', expected <N>[k][b|t]"))?
+            * scale;
+
+        Ok(if tokens {
+            Budget::Tokens(value)
+        } else {
+            Budget::Bytes(value)
+        })
+    }
+}
+
+impl fmt::Display for Budget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Budget::Bytes(bytes) => write!(f, "{bytes} bytes"),
+            Budget::Tokens(tokens) => write!(f, "{tokens} tokens"),
+        }
+    }
+}
+
+/// A file attached to the next user turn.
+#[derive(Clone)]
+pub struct Attachment {
+    pub path: PathBuf,
+    pub content: String,
+}
+
+impl Attachment {
+    pub fn tokens(&self) -> usize {
+        self.content.len().div_ceil(BYTES_PER_TOKEN)
+    }
+}
+
+/// The outcome of trying to attach a file.
+pub enum Report {
+    Added { path: PathBuf, bytes: usize },
+    Skipped { path: PathBuf, reason: String },
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Report::Added { path, bytes } => write!(
+                f,
+                "+ {} ({}, ~{} tokens)",
+                path.display(),
+                size(*bytes),
+                bytes.div_ceil(BYTES_PER_TOKEN)
+            ),
+            Report::Skipped { path, reason } => write!(f, "- {} ({reason})", path.display()),
+        }
+    }
+}
+
+/// Files waiting to be sent along with the next user message.
+pub struct Context {
+    budget: Budget,
+    attachments: Vec<Attachment>,
+}
+
+impl Context {
+    pub fn new(budget: Budget) -> Self {
+        Context {
+            budget,
+            attachments: vec![],
+        }
+    }
+
+    pub fn attachments(&self) -> &[Attachment] {
+        &self.attachments
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.attachments.iter().map(|a| a.content.len()).sum()
+    }
+
+    pub fn budget(&self) -> Budget {
+        self.budget
+    }
+
+    /// Attach a file, a directory (recursively) or a glob pattern.
+    /// A leading `@` is accepted, as typed in the REPL.
+    pub fn attach(&mut self, reference: &str) -> Result<Vec<Report>> {
+        let reference = reference.strip_prefix('@').unwrap_or(reference);
+
+        let mut paths = vec![];
+        if reference.contains(['*', '?', '[']) {
+            for entry in glob::glob(reference)? {
+                paths.push(entry?);
+            }
+            if paths.is_empty() {
+                return Err(anyhow!("No files match {reference}"));
+            }
+        } else {
+            paths.push(PathBuf::from(reference));
+        }
+
+        let mut files = vec![];
+        for path in paths {
+            walk(&path, &mut files)?;
+        }
+
+        Ok(files.into_iter().map(|path| self.add(path)).collect())
+    }
+
+    /// Insert text which does not come from the file system, e.g. a remote resource.
+    pub fn insert(&mut self, path: PathBuf, content: String) -> Report {
+        if let Some(report) = self.check(&path, content.len()) {
+            return report;
+        }
+
+        let bytes = content.len();
+        self.attachments.push(Attachment {
+            path: path.clone(),
+            content,
+        });
+        Report::Added { path, bytes }
+    }
+
+    fn add(&mut self, path: PathBuf) -> Report {
+        let skip = |reason: &str| Report::Skipped {
+            path: path.clone(),
+            reason: reason.to_string(),
+        };
+
+        // Files over the budget are skipped before being read, rather than
+        // loading whole trees such as target/ into memory
+        let len = match fs::metadata(&path) {
+            Ok(metadata) => usize::try_from(metadata.len()).unwrap_or(usize::MAX),
+            Err(error) => return skip(&error.to_string()),
+        };
+        if let Some(report) = self.check(&path, len) {
+            return report;
+        }
+
+        let bytes = match fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(error) => return skip(&error.to_string()),
+        };
+
+        // Treat files with NUL bytes, or not valid UTF-8, as binaries
+        if bytes.contains(&0) {
+            return skip("binary");
+        }
+        let Ok(content) = String::from_utf8(bytes) else {
+            return skip("binary");
+        };
+
+        self.insert(path, content)
+    }
+
+    fn check(&self, path: &Path, bytes: usize) -> Option<Report> {
+        let skip = |reason: String| {
+            Some(Report::Skipped {
+                path: path.to_path_buf(),
+                reason,
+            })
+        };
+
+        if self.attachments.iter().any(|a| a.path == path) {
+            return skip("already attached".to_string());
+        }
+        if self.bytes() + bytes > self.budget.bytes() {
+            return skip(format!("{} over the {} budget", size(bytes), self.budget));
+        }
+        None
+    }
+
+    /// Remove every attachment whose path is, or is under, `path`.
+    pub fn detach(&mut self, path: &Path) -> usize {
+        let path = path
+            .to_str()
+            .and_then(|path| path.strip_prefix('@'))
+            .map(Path::new)
+            .unwrap_or(path);
+
+        let count = self.attachments.len();
+        self.attachments.retain(|a| !a.path.starts_with(path));
+        count - self.attachments.len()
+    }
+
+    /// Render the attachments as fenced, path labelled, blocks and clear them.
+    pub fn take(&mut self) -> Option<String> {
+        if self.attachments.is_empty() {
+            return None;
+        }
+
+        let blocks = self
+            .attachments
+            .drain(..)
+            .map(|a| {
+                // The fence must be longer than any backtick run in the file
+                let longest = a
+                    .content
+                    .split(|c| c != '`')
+                    .map(str::len)
+                    .max()
+                    .unwrap_or(0);
+                let fence = "`".repeat(longest.max(2) + 1);
+                let lang = a
+                    .path
+                    .extension()
+                    .and_then(|ext| ext.to_str())
+                    .unwrap_or("");
+
+                format!(
+                    "{}\n{fence}{lang}\n{}\n{fence}",
+                    a.path.display(),
+                    a.content.trim_end()
+                )
+            })
+            .collect::<Vec<_>>();
+
+        Some(blocks.join("\n\n"))
+    }
+}
+
+/// Collect the files under `path`, or `path` itself if it is not a directory.
+/// Symlinked directories are not followed, as they may loop back up the tree.
+pub(crate) fn walk(path: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    if !path.is_dir() {
+        files.push(path.to_path_buf());
+        return Ok(());
+    }
+    walk_dir(path, files)
+}
+
+fn walk_dir(path: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    let mut entries = fs::read_dir(path)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort();
+
+    for entry in entries {
+        // Skip hidden files and directories, such as .git
+        let hidden = entry
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with('.'));
+        if hidden {
+            continue;
+        }
+
+        let metadata = fs::symlink_metadata(&entry)?;
+        if metadata.is_dir() {
+            walk_dir(&entry, files)?;
+        } else if !metadata.is_symlink() || !entry.is_dir() {
+            files.push(entry);
+        }
+    }
+    Ok(())
+}
+
+pub fn size(bytes: usize) -> String {
+    if bytes < 1024 {
+        format!("{bytes} B")
+    } else {
+        format!("{:.1} KiB", bytes as f64 / 1024.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_budget_parse() {
+        assert_eq!("512".parse::<Budget>().unwrap(), Budget::Bytes(512));
+        assert_eq!("64kb".parse::<Budget>().unwrap(), Budget::Bytes(65536));
+        assert_eq!("8kt".parse::<Budget>().unwrap(), Budget::Tokens(8192));
+        assert!("lots".parse::<Budget>().is_err());
+    }
+
+    #[test]
+    fn test_attach_and_take() {
+        let mut context = Context::new(Budget::Bytes(64));
+
+        assert!(matches!(
+            context.insert(PathBuf::from("a.rs"), "fn a() {}".to_string()),
+            Report::Added { bytes: 9, .. }
+        ));
+        assert!(matches!(
+            context.insert(PathBuf::from("a.rs"), "fn a() {}".to_string()),
+            Report::Skipped { .. }
+        ));
+        assert!(matches!(
+            context.insert(PathBuf::from("big.txt"), "x".repeat(64)),
+            Report::Skipped { .. }
+        ));
+        context.insert(PathBuf::from("b.md"), "```\ncode\n```".to_string());
+
+        assert_eq!(context.detach(Path::new("@a.rs")), 1);
+        assert_eq!(
+            context.take().unwrap(),
+            "b.md\n````md\n```\ncode\n```\n````"
+        );
+        assert!(context.take().is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_attach_tree() {
+        let root = std::env::temp_dir().join(format!("octo-context-{}", std::process::id()));
+        fs::create_dir_all(root.join("src")).unwrap();
+        fs::write(root.join("src/lib.rs"), "fn a() {}").unwrap();
+        fs::write(root.join("big.txt"), "x".repeat(1024)).unwrap();
+
+        // A link back up the tree is not followed
+        std::os::unix::fs::symlink(&root, root.join("src/loop")).unwrap();
+
+        let mut context = Context::new(Budget::Bytes(64));
+        let reports = context.attach(root.to_str().unwrap()).unwrap();
+        let reports = reports.iter().map(ToString::to_string).collect::<Vec<_>>();
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(reports.len(), 2, "{reports:?}");
+        assert!(reports[0].ends_with("big.txt (1.0 KiB over the 64 bytes budget)"));
+        assert!(reports[1].contains("lib.rs (9 B"));
+    }
+}
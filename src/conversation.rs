@@ -1,7 +1,11 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
 use std::fmt;
 
+use crate::chat::{Data, Settings};
+use crate::tools::Tools;
+
 pub enum Role {
     System,
     Assistant,
@@ -27,6 +31,13 @@ pub enum State<'a> {
     OutOfCharacters,
     ContentFilter,
     ToolCalls,
+    /// A tool is being called, with its name and JSON arguments.
+    Tool(&'a str, &'a str),
+    /// Tokens used by the whole reply, tool calls included, if reported.
+    Usage(Usage),
+    /// The request failed for the given reason and is tried again, in the
+    /// given number of seconds, counted down.
+    Retry(&'a str, u64),
     Done,
 }
 
@@ -39,15 +50,107 @@ impl<'a> fmt::Display for State<'a> {
             State::OutOfCharacters => write!(f, "length"),
             State::ContentFilter => write!(f, "content_filter"),
             State::ToolCalls => write!(f, "tool_calls"),
+            State::Tool(name, arguments) => write!(f, "tool: {} {}", name, arguments),
+            State::Usage(usage) => write!(
+                f,
+                "usage: {} + {} tokens",
+                usage.prompt_tokens, usage.completion_tokens
+            ),
+            State::Retry(reason, seconds) => write!(f, "retry: {} in {}s", reason, seconds),
             State::Done => write!(f, "done"),
         }
     }
 }
 
+/// Tokens consumed by a reply, as counted by the provider.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct Usage {
+    #[serde(default)]
+    pub prompt_tokens: i64,
+
+    #[serde(default)]
+    pub completion_tokens: i64,
+
+    #[serde(default)]
+    pub total_tokens: i64,
+}
+
+impl std::ops::AddAssign for Usage {
+    fn add_assign(&mut self, other: Usage) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.total_tokens += other.total_tokens;
+    }
+}
+
+/// What the provider told about the last reply, when it did.
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct Metadata {
+    /// The model which actually replied, which may be more specific than the
+    /// one asked for.
+    pub model: Option<String>,
+    pub system_fingerprint: Option<String>,
+    pub finish_reason: Option<String>,
+
+    /// Summed over the rounds of tool calls.
+    pub usage: Option<Usage>,
+}
+
+impl Metadata {
+    pub fn add_usage(&mut self, usage: Usage) {
+        *self.usage.get_or_insert_with(Usage::default) += usage;
+    }
+}
+
+/// Callback receiving the conversation state as the reply comes in.
+pub type Callback<'a> = &'a (dyn Fn(State) + Send + Sync);
+
 #[async_trait]
-pub trait Conversation {
-    fn build(&mut self, role: Role, message: &str) -> &mut Self;
-    async fn send<F>(&mut self, f: F) -> Result<()>
-    where
-        F: Fn(State) + Send;
+pub trait Conversation: Send + Sync {
+    fn build(&mut self, role: Role, message: &str) -> &mut dyn Conversation;
+
+    /// Set, or replace, the system prompt leading the conversation.
+    fn set_system(&mut self, prompt: &str) -> &mut dyn Conversation;
+
+    /// The current system prompt, if any.
+    fn system(&self) -> Option<&str>;
+
+    fn model(&self) -> &str;
+
+    fn set_model(&mut self, model: &str);
+
+    fn settings(&self) -> &Settings;
+
+    fn settings_mut(&mut self) -> &mut Settings;
+
+    fn history(&self) -> &[Data];
+
+    /// Metadata of the last reply.
+    fn metadata(&self) -> &Metadata;
+
+    /// Replace the conversation history, e.g. with one loaded from a session file.
+    fn restore(&mut self, history: Vec<Data>);
+
+    /// Set the tools the model may call, failing on providers without
+    /// function calling support rather than leaving them unused.
+    fn set_tools(&mut self, tools: Tools) -> Result<()> {
+        match tools.is_empty() {
+            true => Ok(()),
+            false => Err(anyhow!(
+                "Tools are not supported by this provider, only by the OpenAI compatible ones"
+            )),
+        }
+    }
+
+    /// Take the tools out, leaving none, e.g. for a side conversation.
+    fn take_tools(&mut self) -> Tools {
+        Tools::default()
+    }
+
+    /// Models available from the provider.
+    async fn models(&self) -> Result<Vec<String>> {
+        Err(anyhow!("Listing models is not supported by this provider"))
+    }
+
+    async fn send(&mut self, f: Callback<'_>) -> Result<()>;
 }
@@ -0,0 +1,401 @@
+use std::{
+    error::Error,
+    fmt, io,
+    process::ExitCode,
+    time::{Duration, SystemTime},
+};
+
+use reqwest::{header::HeaderMap, StatusCode};
+
+/// What the provider told about a failed request.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Details {
+    pub status: Option<StatusCode>,
+    pub message: String,
+
+    /// The provider's own error type and code, e.g. `invalid_request_error`
+    /// and `context_length_exceeded`.
+    pub type_: Option<String>,
+    pub code: Option<String>,
+
+    /// The request field the error is about.
+    pub param: Option<String>,
+
+    /// The response body as received, when there is one.
+    pub body: Option<String>,
+
+    /// How long the server asked to wait before trying again.
+    pub retry_after: Option<Duration>,
+}
+
+impl Details {
+    /// A response with a failed status, to be completed with what the
+    /// provider says in the body, if it can be parsed.
+    pub fn response(status: StatusCode, headers: &HeaderMap, body: String) -> Self {
+        Details {
+            status: Some(status),
+            body: (!body.trim().is_empty()).then_some(body),
+            retry_after: after(headers),
+            ..Default::default()
+        }
+    }
+
+    /// Add what the provider says about the error.
+    pub fn with(self, said: Details) -> Self {
+        Details {
+            message: said.message,
+            type_: said.type_,
+            code: said.code,
+            param: said.param,
+            ..self
+        }
+    }
+}
+
+/// Why talking to the provider failed, so that octo can react to the cause.
+#[derive(Debug)]
+pub enum OctoError {
+    /// The API key is missing, invalid, or not allowed to do this.
+    Auth(Details),
+
+    /// Too many requests or tokens, or no quota left.
+    RateLimit(Details),
+
+    /// The conversation does not fit the context window of the model.
+    ContextLength(Details),
+
+    /// The request was rejected, e.g. for an unknown model or a bad setting.
+    InvalidRequest(Details),
+
+    /// The request was rejected by the provider content policy.
+    ContentPolicy(Details),
+
+    /// The server failed or is overloaded.
+    Server(Details),
+
+    /// The connection failed, or dropped before the reply was done.
+    Network { message: String, transient: bool },
+
+    /// The provider replied with something octo does not understand.
+    Protocol(String),
+}
+
+impl From<Details> for OctoError {
+    /// Classify an error from its status, and the provider's type and code.
+    fn from(details: Details) -> Self {
+        let status = details.status.map(|status| status.as_u16());
+        let names = [details.type_.as_deref(), details.code.as_deref()];
+        let named = |candidates: &[&str]| names.iter().flatten().any(|n| candidates.contains(n));
+        let message = details.message.to_lowercase();
+        let says = |phrases: &[&str]| phrases.iter().any(|phrase| message.contains(phrase));
+
+        if named(&["context_length_exceeded"])
+            || says(&["context length", "context window", "prompt is too long"])
+        {
+            OctoError::ContextLength(details)
+        } else if named(&["content_policy_violation", "content_filter"]) {
+            OctoError::ContentPolicy(details)
+        } else if matches!(status, Some(401 | 403))
+            || named(&[
+                "authentication_error",
+                "permission_error",
+                "invalid_api_key",
+                "UNAUTHENTICATED",
+                "PERMISSION_DENIED",
+            ])
+            || says(&["api key not valid"])
+        {
+            OctoError::Auth(details)
+        } else if status == Some(429)
+            || named(&[
+                "rate_limit_error",
+                "rate_limit_exceeded",
+                "RESOURCE_EXHAUSTED",
+            ])
+        {
+            OctoError::RateLimit(details)
+        } else if matches!(status, Some(500..=599))
+            || named(&[
+                "api_error",
+                "overloaded_error",
+                "server_error",
+                "UNAVAILABLE",
+            ])
+        {
+            OctoError::Server(details)
+        } else {
+            OctoError::InvalidRequest(details)
+        }
+    }
+}
+
+impl OctoError {
+    /// A request which could not complete, transient when the connection
+    /// was dropped or timed out rather than refused.
+    pub fn network<E: Error + 'static>(error: E) -> Self {
+        let mut transient = false;
+        let mut source: Option<&(dyn Error + 'static)> = Some(&error);
+        while let Some(cause) = source {
+            let kind = cause.downcast_ref::<io::Error>().map(io::Error::kind);
+            transient |= matches!(
+                kind,
+                Some(
+                    io::ErrorKind::ConnectionReset
+                        | io::ErrorKind::ConnectionAborted
+                        | io::ErrorKind::BrokenPipe
+                        | io::ErrorKind::UnexpectedEof
+                        | io::ErrorKind::TimedOut
+                )
+            );
+
+            // Closed by the server before the response, or timed out
+            transient |= cause
+                .downcast_ref::<hyper::Error>()
+                .is_some_and(hyper::Error::is_incomplete_message);
+            transient |= cause
+                .downcast_ref::<reqwest::Error>()
+                .is_some_and(reqwest::Error::is_timeout);
+            source = cause.source();
+        }
+
+        OctoError::Network {
+            message: error.to_string(),
+            transient,
+        }
+    }
+
+    /// The stream ended before the reply was done.
+    pub fn ended() -> Self {
+        OctoError::Network {
+            message: "The stream ended before the reply was done".to_string(),
+            transient: true,
+        }
+    }
+
+    pub fn protocol<E: fmt::Display>(error: E) -> Self {
+        OctoError::Protocol(error.to_string())
+    }
+
+    pub fn details(&self) -> Option<&Details> {
+        match self {
+            OctoError::Auth(details)
+            | OctoError::RateLimit(details)
+            | OctoError::ContextLength(details)
+            | OctoError::InvalidRequest(details)
+            | OctoError::ContentPolicy(details)
+            | OctoError::Server(details) => Some(details),
+            OctoError::Network { .. } | OctoError::Protocol(_) => None,
+        }
+    }
+
+    /// Whether trying again later may work: rate limits, but not an empty
+    /// quota, overloaded servers, and dropped connections.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            OctoError::RateLimit(details) => details.code.as_deref() != Some("insufficient_quota"),
+            OctoError::Server(details) => {
+                matches!(
+                    details.status.map(|s| s.as_u16()),
+                    None | Some(500 | 502 | 503 | 529)
+                )
+            }
+            OctoError::Network { transient, .. } => *transient,
+            _ => false,
+        }
+    }
+
+    /// How long the server asked to wait before trying again.
+    pub fn retry_after(&self) -> Option<Duration> {
+        self.details().and_then(|details| details.retry_after)
+    }
+
+    /// The cause in a word, for machine readable output.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            OctoError::Auth(_) => "auth",
+            OctoError::RateLimit(_) => "rate_limit",
+            OctoError::ContextLength(_) => "context_length",
+            OctoError::InvalidRequest(_) => "invalid_request",
+            OctoError::ContentPolicy(_) => "content_policy",
+            OctoError::Server(_) => "server",
+            OctoError::Network { .. } => "network",
+            OctoError::Protocol(_) => "protocol",
+        }
+    }
+
+    /// What the user may do about it.
+    pub fn hint(&self) -> Option<&'static str> {
+        match self {
+            OctoError::Auth(_) => Some("Check the API key, given with --api-key or in <PROVIDER>_API_KEY"),
+            OctoError::RateLimit(_) => Some("Rate limited by the provider, wait before sending again"),
+            OctoError::ContextLength(_) => Some(
+                "The conversation is too long for the model, set its size with --context-window to fit it",
+            ),
+            OctoError::ContentPolicy(_) => Some("Rejected by the provider content policy, rephrase the message"),
+            _ => None,
+        }
+    }
+
+    /// The exit status of one-shot mode, 3 matching replies stopped by the
+    /// content filter.
+    pub fn exit_code(&self) -> ExitCode {
+        ExitCode::from(match self {
+            OctoError::ContentPolicy(_) => 3,
+            OctoError::Auth(_) => 5,
+            OctoError::RateLimit(_) => 6,
+            OctoError::ContextLength(_) => 7,
+            OctoError::Server(_) | OctoError::Network { .. } => 8,
+            OctoError::InvalidRequest(_) | OctoError::Protocol(_) => 1,
+        })
+    }
+}
+
+impl fmt::Display for OctoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OctoError::Network { message, .. } => write!(f, "{message}"),
+            OctoError::Protocol(message) => write!(f, "Unexpected reply: {message}"),
+            _ => {
+                let details = self.details().unwrap();
+                let status = details.status.map(|status| status.to_string());
+                let parts = [&status, &details.type_, &details.code, &details.param]
+                    .into_iter()
+                    .flatten()
+                    .map(String::as_str)
+                    .collect::<Vec<_>>();
+
+                match (details.message.as_str(), &status) {
+                    ("", Some(status)) => write!(f, "Invalid status code: {status}"),
+                    (message, _) if parts.is_empty() => write!(f, "{message}"),
+                    (message, _) => write!(f, "{message} ({})", parts.join(", ")),
+                }
+            }
+        }
+    }
+}
+
+impl Error for OctoError {}
+
+/// How long the server asked to wait, from `Retry-After` in seconds or as
+/// an HTTP date, or else the longest of the OpenAI `x-ratelimit-reset-*`
+/// durations.
+fn after(headers: &HeaderMap) -> Option<Duration> {
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+
+    if let Some(value) = header("retry-after").map(str::trim) {
+        if let Ok(seconds) = value.parse() {
+            return Duration::try_from_secs_f64(seconds).ok();
+        }
+        if let Ok(date) = httpdate::parse_http_date(value) {
+            return Some(date.duration_since(SystemTime::now()).unwrap_or_default());
+        }
+    }
+    ["x-ratelimit-reset-requests", "x-ratelimit-reset-tokens"]
+        .into_iter()
+        .filter_map(|name| header(name).and_then(duration))
+        .max()
+}
+
+/// Parse durations as formatted by Go, e.g. `1s`, `6m0s` or `20ms`.
+fn duration(text: &str) -> Option<Duration> {
+    let mut rest = text.trim();
+    if rest.is_empty() {
+        return None;
+    }
+
+    let mut seconds = 0.0;
+    while !rest.is_empty() {
+        let end = rest.find(|c: char| !c.is_ascii_digit() && c != '.')?;
+        let (number, tail) = rest.split_at(end);
+        let end = tail
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(tail.len());
+        let (unit, tail) = tail.split_at(end);
+
+        let scale = match unit {
+            "h" => 3600.0,
+            "m" => 60.0,
+            "s" => 1.0,
+            "ms" => 1e-3,
+            "us" | "µs" => 1e-6,
+            "ns" => 1e-9,
+            _ => return None,
+        };
+        seconds += number.parse::<f64>().ok()? * scale;
+        rest = tail;
+    }
+    Duration::try_from_secs_f64(seconds).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn said(type_: &str, code: Option<&str>, message: &str) -> Details {
+        Details {
+            message: message.to_string(),
+            type_: Some(type_.to_string()),
+            code: code.map(str::to_string),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(duration("6m0s"), Some(Duration::from_secs(360)));
+        assert_eq!(duration("1.5s"), Some(Duration::from_millis(1500)));
+        assert_eq!(duration("20ms"), Some(Duration::from_millis(20)));
+        assert_eq!(duration("soon"), None);
+
+        let mut headers = HeaderMap::new();
+        headers.insert("retry-after", "2".parse().unwrap());
+        let response = Details::response(StatusCode::BAD_REQUEST, &headers, "{}".to_string());
+        assert_eq!(response.retry_after, Some(Duration::from_secs(2)));
+
+        let later = SystemTime::now() + Duration::from_secs(120);
+        let date = HeaderMap::from_iter([(
+            "retry-after".parse().unwrap(),
+            httpdate::fmt_http_date(later).parse().unwrap(),
+        )]);
+        let waited = after(&date).unwrap();
+        assert!(waited > Duration::from_secs(115) && waited <= Duration::from_secs(120));
+        let past = HeaderMap::from_iter([(
+            "retry-after".parse().unwrap(),
+            "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap(),
+        )]);
+        assert_eq!(after(&past), Some(Duration::ZERO));
+
+        let error = OctoError::from(response.clone().with(said(
+            "invalid_request_error",
+            Some("context_length_exceeded"),
+            "This model's maximum context length is 8192 tokens",
+        )));
+        assert!(matches!(error, OctoError::ContextLength(_)));
+        assert_eq!(
+            error.to_string(),
+            "This model's maximum context length is 8192 tokens \
+             (400 Bad Request, invalid_request_error, context_length_exceeded)"
+        );
+
+        let error = OctoError::from(said("overloaded_error", None, "Overloaded"));
+        assert!(matches!(error, OctoError::Server(_)));
+        assert!(error.is_transient());
+
+        let quota = Details {
+            status: Some(StatusCode::TOO_MANY_REQUESTS),
+            ..said("insufficient_quota", Some("insufficient_quota"), "No quota")
+        };
+        let error = OctoError::from(quota);
+        assert!(matches!(error, OctoError::RateLimit(_)));
+        assert!(!error.is_transient());
+        assert_eq!(error.exit_code(), ExitCode::from(6));
+
+        let error = OctoError::from(Details::response(
+            StatusCode::UNAUTHORIZED,
+            &HeaderMap::new(),
+            String::new(),
+        ));
+        assert!(matches!(error, OctoError::Auth(_)));
+        assert_eq!(error.to_string(), "Invalid status code: 401 Unauthorized");
+    }
+}
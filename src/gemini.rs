@@ -0,0 +1,648 @@
+use crate::chat::{self, Data, Settings};
+use crate::conversation::{Callback, Conversation, Metadata, Role, State, Usage};
+use crate::error::{Details, OctoError};
+use crate::provider::Auth;
+
+use std::ops::AddAssign;
+
+use anyhow::Result;
+use reqwest::{header::HeaderMap, Client, StatusCode};
+use reqwest_eventsource::{Event, EventSource};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use async_trait::async_trait;
+use tokio_stream::StreamExt;
+
+#[derive(Deserialize, Serialize, Debug)]
+struct Part {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct Content {
+    /// Either `user` or `model`, omitted for the system instruction.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    role: Option<String>,
+
+    #[serde(default)]
+    parts: Vec<Part>,
+}
+
+impl Content {
+    fn new(role: Option<&str>, text: &str) -> Self {
+        Content {
+            role: role.map(str::to_string),
+            parts: vec![Part {
+                text: Some(text.to_string()),
+            }],
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct GenerationConfig {
+    temperature: f64,
+    top_p: f64,
+    max_output_tokens: i64,
+    candidate_count: i64,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop_sequences: Option<Vec<String>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<i64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    presence_penalty: Option<f64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    frequency_penalty: Option<f64>,
+
+    /// `application/json` in JSON mode.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_mime_type: Option<&'static str>,
+
+    /// The schema the reply must match, in the subset Gemini accepts.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_schema: Option<Value>,
+}
+
+/// Keywords of a JSON schema Gemini accepts in `responseSchema`, the others
+/// are rejected with a 400. The reply is still checked against the whole
+/// schema once received.
+const SCHEMA_KEYWORDS: &[&str] = &[
+    "type",
+    "format",
+    "description",
+    "nullable",
+    "enum",
+    "items",
+    "minItems",
+    "maxItems",
+    "properties",
+    "required",
+    "minimum",
+    "maximum",
+];
+
+/// Convert a JSON schema to the OpenAPI subset of `responseSchema`, where
+/// types are upper case and `["string", "null"]` is a nullable string.
+fn response_schema(schema: &Value) -> Value {
+    let Value::Object(object) = schema else {
+        return schema.clone();
+    };
+
+    let mut converted = Map::new();
+    for (key, value) in object {
+        if !SCHEMA_KEYWORDS.contains(&key.as_str()) {
+            continue;
+        }
+        let value = match (key.as_str(), value) {
+            ("type", Value::String(name)) => Value::String(name.to_uppercase()),
+            ("type", Value::Array(names)) => {
+                if names.iter().any(|name| name == "null") {
+                    converted.insert("nullable".to_string(), Value::Bool(true));
+                }
+                let name = names
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .find(|&name| name != "null")
+                    .unwrap_or("null");
+                Value::String(name.to_uppercase())
+            }
+            ("items", items) => response_schema(items),
+            ("properties", Value::Object(properties)) => Value::Object(
+                properties
+                    .iter()
+                    .map(|(name, property)| (name.clone(), response_schema(property)))
+                    .collect(),
+            ),
+            _ => value.clone(),
+        };
+        converted.insert(key.clone(), value);
+    }
+    Value::Object(converted)
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Request {
+    contents: Vec<Content>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system_instruction: Option<Content>,
+
+    generation_config: GenerationConfig,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Candidate {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<Content>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    finish_reason: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    index: Option<i64>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct UsageMetadata {
+    #[serde(default)]
+    prompt_token_count: i64,
+
+    #[serde(default)]
+    candidates_token_count: i64,
+
+    #[serde(default)]
+    total_token_count: i64,
+}
+
+#[derive(Deserialize, Debug)]
+struct ErrorObject {
+    code: i64,
+    message: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<String>,
+}
+
+impl From<ErrorObject> for Details {
+    fn from(error: ErrorObject) -> Self {
+        Details {
+            status: u16::try_from(error.code)
+                .ok()
+                .and_then(|code| StatusCode::from_u16(code).ok()),
+            message: error.message,
+            type_: error.status,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum Response {
+    Error {
+        error: ErrorObject,
+    },
+    #[serde(rename_all = "camelCase")]
+    Completion {
+        #[serde(default)]
+        candidates: Vec<Candidate>,
+
+        #[serde(skip_serializing_if = "Option::is_none")]
+        usage_metadata: Option<UsageMetadata>,
+
+        #[serde(skip_serializing_if = "Option::is_none")]
+        model_version: Option<String>,
+    },
+}
+
+/// Map Gemini's finish reason onto the conversation state.
+fn finish(reason: &str) -> State<'static> {
+    match reason {
+        "MAX_TOKENS" => State::OutOfCharacters,
+        "SAFETY" | "RECITATION" | "BLOCKLIST" | "PROHIBITED_CONTENT" | "SPII" => {
+            State::ContentFilter
+        }
+        _ => State::Stop,
+    }
+}
+
+/// The error of a response with a failed status, with what Gemini says in
+/// its body.
+fn failure(status: StatusCode, headers: &HeaderMap, body: String) -> anyhow::Error {
+    let said = match serde_json::from_str::<Response>(&body) {
+        Ok(Response::Error { error }) => Some(error),
+        _ => None,
+    };
+    let mut details = Details::response(status, headers, body);
+    if let Some(error) = said {
+        details = details.with(error.into());
+    }
+    OctoError::from(details).into()
+}
+
+/// Conversation through the Google AI `generateContent` REST API.
+pub struct Gemini {
+    client: Client,
+    api_key: String,
+
+    /// The API base, e.g. `https://generativelanguage.googleapis.com/v1beta`.
+    url: String,
+    model: String,
+    settings: Settings,
+    history: Vec<Data>,
+    auth: Auth,
+    metadata: Metadata,
+}
+
+impl Gemini {
+    pub fn new(api_key: &str, url: &str, model: &str, settings: &Settings) -> Self {
+        Gemini {
+            client: Client::new(),
+            api_key: api_key.to_string(),
+            url: url.trim_end_matches('/').to_string(),
+            model: model.to_string(),
+            settings: settings.clone(),
+            history: vec![],
+            auth: Auth::Header("x-goog-api-key"),
+            metadata: Metadata::default(),
+        }
+    }
+
+    pub fn auth(mut self, auth: Auth) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    fn post(&self, method: &str) -> reqwest::RequestBuilder {
+        let builder = self.client.post(self.endpoint(method));
+        self.auth.apply(builder, &self.api_key)
+    }
+
+    fn request(&self) -> Request {
+        let mut system_instruction = None;
+        let mut contents = vec![];
+
+        for data in &self.history {
+            let text = data.content.as_deref().unwrap_or_default();
+            match data.role.as_deref() {
+                Some("system") => system_instruction = Some(Content::new(None, text)),
+                Some("assistant") => contents.push(Content::new(Some("model"), text)),
+                _ => contents.push(Content::new(Some("user"), text)),
+            }
+        }
+
+        let penalty = |value: f64| (value != 0.0).then_some(value);
+
+        Request {
+            contents,
+            system_instruction,
+            generation_config: GenerationConfig {
+                temperature: self.settings.temperature,
+                top_p: self.settings.top_p,
+                max_output_tokens: self.settings.max_tokens,
+                candidate_count: self.settings.n,
+                stop_sequences: self.settings.stop.clone(),
+                seed: self.settings.seed,
+                presence_penalty: penalty(self.settings.presence_penalty),
+                frequency_penalty: penalty(self.settings.frequency_penalty),
+                response_mime_type: (self.settings.json || self.settings.schema.is_some())
+                    .then_some("application/json"),
+                response_schema: self.settings.schema.as_ref().map(response_schema),
+            },
+        }
+    }
+
+    fn endpoint(&self, method: &str) -> String {
+        format!("{}/models/{}:{}", self.url, self.model, method)
+    }
+
+    /// Forward the first candidate of a response, returning its finish reason.
+    fn reply(
+        response: Response,
+        text: &mut String,
+        metadata: &mut Metadata,
+        f: Callback<'_>,
+    ) -> Result<Option<String>> {
+        match response {
+            Response::Error { error } => Err(OctoError::from(Details::from(error)).into()),
+            Response::Completion {
+                candidates,
+                usage_metadata,
+                model_version,
+            } => {
+                // Streamed chunks carry the counts so far, not a delta
+                if let Some(usage) = usage_metadata {
+                    metadata.usage = Some(Usage {
+                        prompt_tokens: usage.prompt_token_count,
+                        completion_tokens: usage.candidates_token_count,
+                        total_tokens: usage.total_token_count,
+                    });
+                }
+                if model_version.is_some() {
+                    metadata.model = model_version;
+                }
+
+                let Some(candidate) = candidates.into_iter().next() else {
+                    return Ok(None);
+                };
+
+                let parts = candidate.content.map(|content| content.parts);
+                for chunk in parts.unwrap_or_default().into_iter().filter_map(|p| p.text) {
+                    text.add_assign(&chunk);
+                    f(State::Message(&chunk));
+                }
+
+                if candidate.finish_reason.is_some() {
+                    metadata.finish_reason.clone_from(&candidate.finish_reason);
+                }
+                Ok(candidate.finish_reason)
+            }
+        }
+    }
+
+    async fn generate(&self, metadata: &mut Metadata, f: Callback<'_>) -> Result<String> {
+        let response = self
+            .post("generateContent")
+            .json(&self.request())
+            .send()
+            .await
+            .map_err(OctoError::network)?;
+
+        let (status, headers) = (response.status(), response.headers().clone());
+        let body = response.text().await.map_err(OctoError::network)?;
+        if !status.is_success() {
+            return Err(failure(status, &headers, body));
+        }
+        let response = serde_json::from_str::<Response>(&body).map_err(OctoError::protocol)?;
+
+        let mut text = String::new();
+        f(State::Start);
+        if let Some(reason) = Self::reply(response, &mut text, metadata, f)? {
+            f(finish(&reason));
+        }
+
+        Ok(text)
+    }
+
+    async fn stream(&self, metadata: &mut Metadata, f: Callback<'_>) -> Result<String> {
+        let builder = self
+            .post("streamGenerateContent")
+            .query(&[("alt", "sse")])
+            .json(&self.request());
+
+        let mut text = String::new();
+
+        let mut es = EventSource::new(builder)?;
+        while let Some(event) = es.next().await {
+            match event {
+                Ok(Event::Open) => {
+                    f(State::Start);
+                }
+                Ok(Event::Message(message)) => {
+                    let response = serde_json::from_str::<Response>(&message.data)
+                        .map_err(OctoError::protocol)?;
+                    match Self::reply(response, &mut text, metadata, f) {
+                        Ok(Some(reason)) => f(finish(&reason)),
+                        Ok(None) => {}
+                        Err(error) => {
+                            es.close();
+                            return Err(error);
+                        }
+                    }
+                }
+                // Gemini has no [DONE] message, the server just closes the stream
+                Err(reqwest_eventsource::Error::StreamEnded) => {
+                    es.close();
+                    return Ok(text);
+                }
+                Err(reqwest_eventsource::Error::InvalidStatusCode(status, response)) => {
+                    es.close();
+                    let headers = response.headers().clone();
+                    let body = response.text().await.unwrap_or_default();
+                    return Err(failure(status, &headers, body));
+                }
+                Err(error) => {
+                    es.close();
+                    return Err(OctoError::network(error).into());
+                }
+            }
+        }
+
+        Ok(text)
+    }
+}
+
+#[async_trait]
+impl Conversation for Gemini {
+    fn build(&mut self, role: Role, message: &str) -> &mut dyn Conversation {
+        self.history.push(Data::new(role, message));
+        self
+    }
+
+    fn set_system(&mut self, prompt: &str) -> &mut dyn Conversation {
+        chat::set_system(&mut self.history, prompt);
+        self
+    }
+
+    fn system(&self) -> Option<&str> {
+        chat::system(&self.history)
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn set_model(&mut self, model: &str) {
+        self.model = model.to_string();
+    }
+
+    fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    fn settings_mut(&mut self) -> &mut Settings {
+        &mut self.settings
+    }
+
+    fn history(&self) -> &[Data] {
+        &self.history
+    }
+
+    fn restore(&mut self, history: Vec<Data>) {
+        self.history = history;
+    }
+
+    fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    async fn send(&mut self, f: Callback<'_>) -> Result<()> {
+        let mut metadata = Metadata::default();
+        let text = if self.settings.stream {
+            self.stream(&mut metadata, f).await?
+        } else {
+            self.generate(&mut metadata, f).await?
+        };
+        if let Some(usage) = metadata.usage {
+            f(State::Usage(usage));
+        }
+        self.metadata = metadata;
+
+        self.history.push(Data::new(Role::Assistant, &text));
+        f(State::Done);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use serde_json::json;
+    use wiremock::{
+        matchers::{body_partial_json, header, method, path, query_param},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    fn gemini(server: &MockServer, stream: bool) -> Gemini {
+        let settings = Settings {
+            stream,
+            temperature: 0.2,
+            max_tokens: 64,
+            ..Default::default()
+        };
+
+        let mut gemini = Gemini::new("key", &server.uri(), "gemini-pro", &settings);
+        gemini.set_system("Be brief").build(Role::User, "Hello");
+        gemini
+    }
+
+    #[tokio::test]
+    async fn test_generate_content() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/models/gemini-pro:generateContent"))
+            .and(header("x-goog-api-key", "key"))
+            .and(body_partial_json(json!({
+                "contents": [{ "role": "user", "parts": [{ "text": "Hello" }] }],
+                "systemInstruction": { "parts": [{ "text": "Be brief" }] },
+                "generationConfig": { "temperature": 0.2, "maxOutputTokens": 64 }
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(
+                include_str!("../tests/fixtures/gemini/generate.json"),
+                "application/json",
+            ))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let states = Mutex::new(vec![]);
+        let mut gemini = gemini(&server, false);
+        gemini
+            .send(&|state| states.lock().unwrap().push(state.to_string()))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            *states.lock().unwrap(),
+            [
+                "start",
+                "message: Hi there!",
+                "stop",
+                "usage: 4 + 3 tokens",
+                "done"
+            ]
+        );
+        assert_eq!(gemini.history().len(), 3);
+        assert_eq!(gemini.history()[2].role.as_deref(), Some("assistant"));
+    }
+
+    #[test]
+    fn test_response_schema() {
+        let settings = Settings {
+            schema: Some(json!({
+                "$schema": "https://json-schema.org/draft/2020-12/schema",
+                "type": "object",
+                "properties": {
+                    "name": { "type": "string", "minLength": 1 },
+                    "tags": { "type": "array", "items": { "type": "string" } },
+                    "note": { "type": ["string", "null"] }
+                },
+                "required": ["name"],
+                "additionalProperties": false
+            })),
+            ..Default::default()
+        };
+        let gemini = Gemini::new("key", "http://localhost", "gemini-pro", &settings);
+        let request = serde_json::to_value(gemini.request()).unwrap();
+        assert_eq!(
+            request["generationConfig"]["responseMimeType"],
+            "application/json"
+        );
+        assert_eq!(
+            request["generationConfig"]["responseSchema"],
+            json!({
+                "type": "OBJECT",
+                "properties": {
+                    "name": { "type": "STRING" },
+                    "tags": { "type": "ARRAY", "items": { "type": "STRING" } },
+                    "note": { "type": "STRING", "nullable": true }
+                },
+                "required": ["name"]
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stream_generate_content() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/models/gemini-pro:streamGenerateContent"))
+            .and(query_param("alt", "sse"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(
+                include_str!("../tests/fixtures/gemini/stream.sse"),
+                "text/event-stream",
+            ))
+            .mount(&server)
+            .await;
+
+        let states = Mutex::new(vec![]);
+        let mut gemini = gemini(&server, true);
+        gemini
+            .send(&|state| states.lock().unwrap().push(state.to_string()))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            *states.lock().unwrap(),
+            [
+                "start",
+                "message: Hi",
+                "message:  there,",
+                "message:  how can I help?",
+                "length",
+                "usage: 4 + 8 tokens",
+                "done"
+            ]
+        );
+        assert_eq!(
+            gemini.history()[2].content.as_deref(),
+            Some("Hi there, how can I help?")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_error_response() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(400).set_body_raw(
+                include_str!("../tests/fixtures/gemini/error.json"),
+                "application/json",
+            ))
+            .mount(&server)
+            .await;
+
+        for stream in [false, true] {
+            let result = gemini(&server, stream).send(&|_| {}).await;
+            let error = result.unwrap_err();
+            assert!(
+                matches!(error.downcast_ref(), Some(OctoError::Auth(_))),
+                "{error}"
+            );
+            assert!(error.to_string().contains("API key not valid"), "{error}");
+        }
+    }
+}
@@ -1,11 +1,54 @@
 #![allow(dead_code)]
 
-use std::io::{self, Write};
+use std::{
+    future::Future,
+    io::{self, IsTerminal, Read, Write},
+    path::{Path, PathBuf},
+    pin::Pin,
+    process::ExitCode,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
 
+mod anthropic;
 mod chat;
+mod command;
+mod config;
+mod context;
 mod conversation;
+mod error;
+mod gemini;
+mod markdown;
+mod mcp;
+mod ollama;
+mod oneshot;
+mod output;
+mod plugins;
+mod pricing;
+mod provider;
+mod retry;
+mod schema;
+mod session;
+mod tools;
+mod window;
+mod workspace;
 
+use crate::chat::Data;
+use crate::command::{Cmd, ContextCmd};
+use crate::config::{Config, Profile};
+use crate::context::{Budget, Context, Report};
 use crate::conversation::{Conversation, Role, State};
+use crate::error::OctoError;
+use crate::markdown::Markdown;
+use crate::output::Output;
+use crate::pricing::{Prices, Tally};
+use crate::provider::Provider;
+use crate::retry::Retry;
+use crate::session::Session;
+use crate::tools::Tools;
+use crate::window::{Fitted, Policy, Window};
 
 use crossterm::{
     cursor, execute,
@@ -14,39 +57,36 @@ use crossterm::{
 };
 
 use anyhow::{anyhow, Result};
-use clap::{Parser, ValueEnum};
-
-#[derive(Clone, Debug, ValueEnum)]
-enum Provider {
-    OpenAI,
-    TogetherAI,
-    MistralAI,
-    Gemini,
-}
+use clap::{builder::PossibleValuesParser, ArgMatches, CommandFactory, FromArgMatches, Parser};
+use rustyline::error::ReadlineError;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)] // Read from `Cargo.toml`
 struct Opts {
     /// Provider API to use
-    #[arg(value_enum, default_value = "open-ai")]
-    provider: Provider,
+    #[arg(default_value = "open-ai", env = "OCTO_PROVIDER", value_parser = PossibleValuesParser::new(provider::names()))]
+    provider: String,
 
     /// API key, uses <PROVIDER>_API_KEY env var if not provided
     #[arg(short, long)]
     api_key: Option<String>,
 
     /// URL provider endpoint
-    #[arg(short, long)]
+    #[arg(short, long, env = "OCTO_URL")]
     url: Option<String>,
 
     /// Model name
-    #[arg(short, long)]
+    #[arg(short, long, env = "OCTO_MODEL")]
     model: Option<String>,
 
     /// Use streaming API for quicker responses
     #[arg(short, long, default_value = "false")]
     stream: bool,
 
+    /// Print the replies in the REPL as they come, without rendering their Markdown
+    #[arg(long)]
+    raw: bool,
+
     /// What sampling temperature to use, between 0 and 2. Higher values like 0.8 will make
     /// the output more random, while lower values like 0.2 will make it more focused and
     /// deterministic
@@ -60,65 +100,867 @@ struct Opts {
     /// If specified, the system will make a best effort to sample deterministically
     #[arg(short = 'c', long)]
     seed: Option<i64>,
+
+    /// Nucleus sampling, only the tokens comprising the top_p probability mass are considered
+    #[arg(long, default_value = "1.0")]
+    top_p: f64,
+
+    /// Number between -2.0 and 2.0. Positive values penalize tokens based on their frequency
+    #[arg(long, default_value = "0.0", allow_hyphen_values = true)]
+    frequency_penalty: f64,
+
+    /// Number between -2.0 and 2.0. Positive values penalize tokens that already appeared
+    #[arg(long, default_value = "0.0", allow_hyphen_values = true)]
+    presence_penalty: f64,
+
+    /// Sequence where the API will stop generating further tokens, can be repeated
+    #[arg(long)]
+    stop: Vec<String>,
+
+    /// Bias a token likelihood, as <TOKEN_ID>=<BIAS> with bias between -100 and 100
+    #[arg(long, value_parser = parse_logit_bias, allow_hyphen_values = true)]
+    logit_bias: Vec<(String, f64)>,
+
+    /// How many chat completion choices to generate for each input message, only the first one is shown
+    #[arg(short, long = "choices", default_value = "1")]
+    n: i64,
+
+    /// The user ID to associate with requests
+    #[arg(long)]
+    user: Option<String>,
+
+    /// System prompt to start the conversation with
+    #[arg(long, conflicts_with = "system_file")]
+    system: Option<String>,
+
+    /// File to read the system prompt from
+    #[arg(long)]
+    system_file: Option<PathBuf>,
+
+    /// Session file to resume the conversation from, and save it to with /save
+    #[arg(long)]
+    session: Option<PathBuf>,
+
+    /// Let the model read, list and search files, and run shell commands once confirmed
+    #[arg(long)]
+    tools: bool,
+
+    /// Directory the tools are restricted to
+    #[arg(long, default_value = ".")]
+    root: PathBuf,
+
+    /// Send a single message, print the reply and exit. Piped stdin is attached to it
+    #[arg(short, long)]
+    prompt: Option<String>,
+
+    /// How one-shot replies are printed, other than text implies one-shot mode
+    #[arg(long, value_enum, default_value_t = Output::Text)]
+    output: Output,
+
+    /// Ask for replies made of a single JSON value
+    #[arg(long)]
+    json: bool,
+
+    /// JSON schema file the replies must match, implies --json
+    #[arg(long)]
+    schema: Option<PathBuf>,
+
+    /// TOML file overriding the model prices, defaults to ~/.config/octo/prices.toml
+    #[arg(long)]
+    prices: Option<PathBuf>,
+
+    /// TOML or JSON file declaring tools run as external commands
+    #[arg(long)]
+    plugins: Option<PathBuf>,
+
+    /// Command starting an MCP server over stdio, whose tools and resources are made available
+    #[arg(long)]
+    mcp: Vec<String>,
+
+    /// How many times to try again requests failing with rate limits, server or connection errors
+    #[arg(long, default_value = "3")]
+    retries: u32,
+
+    /// Try failed requests again even once part of the reply was shown
+    #[arg(long)]
+    retry_partial: bool,
+
+    /// How many rounds of tool calls the model can make before giving a final answer
+    #[arg(long, default_value = "8")]
+    max_tool_iterations: usize,
+
+    /// Maximum size of the files attached with /context, as <N>[k] bytes or <N>[k]t tokens
+    #[arg(long, default_value = "64k")]
+    context_budget: Budget,
+
+    /// Context window of the model in tokens, known for the common models
+    #[arg(long)]
+    context_window: Option<usize>,
+
+    /// What to do with the oldest turns once the conversation outgrows the context window
+    #[arg(long, value_enum, default_value_t = Policy::Drop)]
+    truncate: Policy,
+
+    /// Profile of the configuration file to use
+    #[arg(long, env = "OCTO_PROFILE")]
+    profile: Option<String>,
+
+    /// TOML configuration file with defaults and profiles, defaults to ~/.config/octo/config.toml
+    #[arg(long, env = "OCTO_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Print the options resolved from the command line, environment and configuration, then exit
+    #[arg(long)]
+    print_config: bool,
 }
 
-// TODO - Implement commands
-// enum Cmds {
-//     Exit,
-//     User,
-//     System,
-//     Context,
-//     Save,
-//     Load,
-// }
+fn parse_logit_bias(arg: &str) -> Result<(String, f64)> {
+    let (token, bias) = arg
+        .split_once('=')
+        .ok_or(anyhow!("expected <TOKEN_ID>=<BIAS>, got '{arg}'"))?;
+    Ok((token.trim().to_string(), bias.trim().parse()?))
+}
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let opts = Opts::parse();
+/// The options given on the command line or in the environment, on top of
+/// the profile, on top of the configuration defaults.
+fn configure(matches: &ArgMatches, config: &Config, profile: Option<&str>) -> Result<Opts> {
+    let mut opts = Opts::from_arg_matches(matches)?;
+    config.defaults.apply(&mut opts, matches)?;
+    if let Some(name) = profile {
+        config.profile(name)?.apply(&mut opts, matches)?;
+    }
+    Ok(opts)
+}
 
-    let settings = chat::Settings {
-        stream: opts.stream,
+fn settings(opts: &Opts, one_shot: bool) -> Result<chat::Settings> {
+    Ok(chat::Settings {
+        stream: opts.stream || one_shot,
         temperature: opts.temperature,
+        top_p: opts.top_p,
         max_tokens: opts.max_tokens,
         seed: opts.seed,
+        frequency_penalty: opts.frequency_penalty,
+        presence_penalty: opts.presence_penalty,
+        stop: (!opts.stop.is_empty()).then(|| opts.stop.clone()),
+        logit_bias: (!opts.logit_bias.is_empty())
+            .then(|| opts.logit_bias.iter().cloned().collect()),
+        n: opts.n,
+        user: opts.user.clone(),
+        json: opts.json || opts.schema.is_some(),
+        schema: opts.schema.as_deref().map(schema::load).transpose()?,
+        retry: retry(opts),
+    })
+}
+
+fn retry(opts: &Opts) -> Retry {
+    Retry {
+        attempts: opts.retries,
+        partial: opts.retry_partial,
+        ..Default::default()
+    }
+}
+
+/// The system prompt, from a file or the command line.
+fn system(opts: &Opts) -> Result<Option<String>> {
+    if let Some(path) = &opts.system_file {
+        let prompt = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("Cannot read {}: {e}", path.display()))?;
+        return Ok(Some(prompt.trim().to_string()));
+    }
+    Ok(opts.system.clone())
+}
+
+/// The options of the profile switched to from `current`. The API key and URL
+/// given on the command line are only used with the provider octo `started`
+/// with. Options only read at startup cannot be changed by the profile.
+fn reconfigure(
+    matches: &ArgMatches,
+    config: &Config,
+    name: &str,
+    current: &Opts,
+    started: &str,
+) -> Result<Opts> {
+    let profile = config.profile(name)?;
+    let mut opts = configure(matches, config, Some(name))?;
+    if opts.provider != started {
+        opts.api_key = None;
+        opts.url = profile.url.clone();
+    }
+
+    let fixed = [
+        (
+            "tools",
+            profile.tools.is_some() && opts.tools != current.tools,
+        ),
+        ("root", profile.root.is_some() && opts.root != current.root),
+        (
+            "plugins",
+            profile.plugins.is_some() && opts.plugins != current.plugins,
+        ),
+        ("mcp", profile.mcp.is_some() && opts.mcp != current.mcp),
+        (
+            "prices",
+            profile.prices.is_some() && opts.prices != current.prices,
+        ),
+    ]
+    .into_iter()
+    .filter_map(|(option, changed)| changed.then_some(option))
+    .collect::<Vec<_>>();
+    if !fixed.is_empty() {
+        return Err(anyhow!(
+            "Profile {name} sets {}, which only apply at startup, start octo with --profile {name}",
+            fixed.join(", ")
+        ));
+    }
+    Ok(opts)
+}
+
+/// Connect with the options of a profile, from `reconfigure`, carrying the
+/// conversation over. Tools and MCP servers stay the ones started with octo.
+async fn switch(
+    opts: Opts,
+    chat: &dyn Conversation,
+    tools: &Tools,
+) -> Result<(Opts, &'static Provider, Box<dyn Conversation>)> {
+    let provider = provider::find(&opts.provider)?;
+    let mut switched = provider.connect(
+        opts.api_key.clone(),
+        opts.url.clone(),
+        opts.model.clone(),
+        &settings(&opts, false)?,
+    )?;
+    pick_model(switched.as_mut()).await?;
+
+    switched.restore(chat.history().to_vec());
+    if let Some(prompt) = system(&opts)? {
+        switched.set_system(&prompt);
+    }
+    switched.set_tools(tools.clone())?;
+
+    Ok((opts, provider, switched))
+}
+
+/// Settings given explicitly on the command line or in the environment,
+/// on top of those saved with a session.
+macro_rules! overlay {
+    ($settings:expr, $cli:expr, $matches:expr, { $($field:ident),* }) => {
+        $(
+            if config::given($matches, stringify!($field)) {
+                $settings.$field = $cli.$field.clone();
+            }
+        )*
+    };
+}
+
+/// Restore a saved session into a fresh chat. The API key and URL given on the
+/// command line are only used if the session was saved with the same provider.
+/// The saved settings are used, but for those given explicitly in `cli`.
+fn resume(
+    session: Session,
+    opts: &Opts,
+    cli: &chat::Settings,
+    matches: &ArgMatches,
+) -> Result<(&'static Provider, Box<dyn Conversation>)> {
+    let provider = provider::find(&session.provider)?;
+    let same = provider.name == opts.provider;
+
+    let mut settings = session.settings;
+    overlay!(settings, cli, matches, {
+        stream, temperature, top_p, max_tokens, seed, frequency_penalty, presence_penalty,
+        stop, logit_bias, n, user, json, schema
+    });
+    if config::given(matches, "schema") {
+        settings.json = true;
+    }
+    settings.retry = cli.retry;
+
+    let mut chat = provider.connect(
+        opts.api_key.clone().filter(|_| same),
+        opts.url.clone().filter(|_| same),
+        Some(session.model).filter(|model| !model.is_empty()),
+        &settings,
+    )?;
+    chat.restore(session.history);
+
+    Ok((provider, chat))
+}
+
+/// Local servers have no default model, pick the first one they list.
+async fn pick_model(chat: &mut dyn Conversation) -> Result<()> {
+    if chat.model().is_empty() {
+        let models = chat.models().await?;
+        let model = models
+            .first()
+            .ok_or(anyhow!("The server lists no models, use --model"))?;
+        chat.set_model(model);
+    }
+    Ok(())
+}
+
+/// Asks the user about the commands run by the tools, on stderr so that
+/// stdout only carries the replies.
+struct Terminal;
+
+impl workspace::Console for Terminal {
+    fn confirm(&self, command: &str, cancelled: &AtomicBool) -> bool {
+        let mut stderr = io::stderr();
+
+        // Piped input was read as the message, there is nobody to answer
+        if !io::stdin().is_terminal() {
+            let notice = format!("Refused `{command}`, stdin is not a terminal to confirm it");
+            writeln!(stderr, "{}", notice.yellow()).unwrap();
+            return false;
+        }
+
+        let question = format!("Run `{command}`? [y/N] ");
+        write!(stderr, "{}", question.yellow()).unwrap();
+        stderr.flush().unwrap();
+
+        // Wait for the answer a bit at a time, so that a cancelled turn
+        // doesn't take the next line typed at the prompt
+        #[cfg(unix)]
+        loop {
+            if cancelled.load(Ordering::Relaxed) {
+                writeln!(stderr).unwrap();
+                return false;
+            }
+            let mut stdin = libc::pollfd {
+                fd: libc::STDIN_FILENO,
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            // SAFETY: a single valid pollfd, for as long as the call
+            match unsafe { libc::poll(&mut stdin, 1, 100) } {
+                0 => continue,
+                -1 if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => continue,
+                _ => break,
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = cancelled;
+
+        let mut answer = String::new();
+        io::stdin().read_line(&mut answer).unwrap_or_default();
+        matches!(answer.trim(), "y" | "Y" | "yes")
+    }
+
+    fn output(&self, text: &str) {
+        eprint!("{}", text.dim());
+    }
+}
+
+/// Asks the model to keep going after a reply was cut short.
+const CONTINUE: &str = "Continue exactly where you left off, without repeating anything.";
+
+/// Offered along with a message which could not be sent.
+const RETRY: &str = "The message was not sent, press Enter to send it again, or Ctrl-C to drop it";
+
+/// Print why a message could not be sent, with what may be done about it.
+fn failure(stdout: &mut io::Stdout, error: &anyhow::Error) -> Result<()> {
+    execute!(stdout, style::SetAttribute(style::Attribute::Reset))?;
+    writeln!(stdout, "{}", error.to_string().red())?;
+    if let Some(hint) = error.downcast_ref::<OctoError>().and_then(OctoError::hint) {
+        writeln!(stdout, "{}", hint.dim())?;
+    }
+    writeln!(stdout, "{}", RETRY.dim())?;
+    Ok(())
+}
+
+/// How a reply ended.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Ending {
+    Done,
+
+    /// Cut at the maximum number of tokens.
+    Truncated,
+
+    /// Interrupted with Ctrl-C, after part of the reply came in.
+    Cancelled,
+}
+
+/// Resolves when the user asks to cancel a reply.
+type Interrupt = fn() -> Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Ctrl-C, which cancels the reply rather than exiting.
+fn ctrl_c() -> Pin<Box<dyn Future<Output = ()> + Send>> {
+    Box::pin(async {
+        let _ = tokio::signal::ctrl_c().await;
+    })
+}
+
+/// Where replies are printed, and what cancels them.
+struct Printer<'a> {
+    out: &'a mut (dyn Write + Send),
+
+    /// Print replies as they come, rather than rendered as Markdown.
+    raw: bool,
+    interrupt: Interrupt,
+}
+
+impl<'a> Printer<'a> {
+    /// Print to the terminal, cancelling with Ctrl-C.
+    fn new(out: &'a mut (dyn Write + Send), raw: bool) -> Self {
+        Printer {
+            out,
+            raw,
+            interrupt: ctrl_c,
+        }
+    }
+}
+
+/// Send the conversation and print the reply as it comes in, rendered as
+/// Markdown unless raw, followed by the tokens it used. Interrupting cancels
+/// the request, keeping what came of the reply, or failing if nothing did.
+async fn reply(
+    chat: &mut dyn Conversation,
+    tally: &mut Tally,
+    printer: &mut Printer<'_>,
+) -> Result<Ending> {
+    let raw = printer.raw;
+    let mut stdout = &mut *printer.out;
+
+    writeln!(stdout)?;
+    execute!(&mut stdout, cursor::SavePosition)?;
+
+    // FIXME - Using animated waiting
+    writeln!(stdout, "{}", "Thinking...".italic().blue())?;
+
+    // We are not handling errors, instead we are just bubbling them up.
+    // Therefore, anything caught after this point will be printed in
+    // whatever style we set here.
+    // Assume the worst, prepare the terminal style for errors.
+    execute!(
+        &mut stdout,
+        style::SetAttribute(style::Attribute::Bold),
+        style::SetForegroundColor(style::Color::Red)
+    )?;
+
+    let truncated = AtomicBool::new(false);
+
+    // JSON replies are printed once checked
+    let json = chat.settings().json;
+
+    // Tool calls make the model reply more than once, only the first reply
+    // replaces the waiting message.
+    let started = AtomicBool::new(false);
+
+    // The reply of the current round, kept if cancelled
+    let partial = Mutex::new(String::new());
+
+    // Terminals which don't tell their size report 0 columns
+    let width = match terminal::size() {
+        Ok((columns, _)) if columns > 0 => columns as usize,
+        _ => 80,
+    };
+    let markdown = Mutex::new(Markdown::new(width));
+    let out = Mutex::new(stdout);
+
+    let callback = |state: State| {
+        match &state {
+            State::Start => partial.lock().unwrap().clear(),
+            State::Message(text) => partial.lock().unwrap().push_str(text),
+            State::Retry(..) => {
+                // The text of the failed attempt is cleared off the screen,
+                // and neither kept nor rendered any further
+                partial.lock().unwrap().clear();
+                *markdown.lock().unwrap() = Markdown::new(width);
+            }
+            _ => {}
+        }
+
+        let mut guard = out.lock().unwrap();
+        let mut stdout = &mut **guard;
+
+        // Lines still rendering end with the reply, or before a notice
+        if !matches!(state, State::Message(_)) {
+            write!(stdout, "{}", markdown.lock().unwrap().finish()).unwrap();
+        }
+
+        match state {
+            State::Start if started.swap(true, Ordering::Relaxed) => {}
+            State::Start => {
+                // No errors; reset the terminal style to print out the response message
+                execute!(
+                    &mut stdout,
+                    cursor::RestorePosition,
+                    terminal::Clear(terminal::ClearType::FromCursorDown),
+                    style::SetAttribute(style::Attribute::Reset)
+                )
+                .unwrap();
+            }
+            State::Message(_) if json => {}
+            State::Message(text) if raw => {
+                // Append text response
+                write!(stdout, "{}", text.as_str().italic().blue()).unwrap();
+
+                // Flush stdout after each chunk for a typewriter effect
+                stdout.flush().unwrap();
+            }
+            State::Message(text) => {
+                write!(stdout, "{}", markdown.lock().unwrap().push(text)).unwrap();
+
+                // Flush stdout after each chunk for a typewriter effect
+                stdout.flush().unwrap();
+            }
+            State::OutOfCharacters => {
+                truncated.store(true, Ordering::Relaxed);
+                let notice =
+                    "Reply truncated at the maximum number of tokens, /continue to keep going";
+                writeln!(stdout, "\n\n{}", notice.yellow()).unwrap();
+            }
+            State::Tool(name, arguments) => {
+                writeln!(stdout, "{}", format!("> {name} {arguments}").dim()).unwrap();
+            }
+            State::ContentFilter => {
+                let notice = "Reply stopped by the provider content filter";
+                writeln!(stdout, "\n\n{}", notice.yellow()).unwrap();
+            }
+            State::Retry(reason, seconds) => {
+                // Replaced by the reply once the request goes through
+                started.store(false, Ordering::Relaxed);
+                execute!(
+                    &mut stdout,
+                    cursor::RestorePosition,
+                    terminal::Clear(terminal::ClearType::FromCursorDown)
+                )
+                .unwrap();
+                let notice = format!("{reason}, retrying in {seconds}s");
+                writeln!(stdout, "{}", notice.yellow()).unwrap();
+            }
+            State::Stop | State::Done => {
+                writeln!(stdout).unwrap();
+            }
+            _ => {}
+        }
+    };
+
+    // Dropping the request closes the connection
+    let cancelled = tokio::select! {
+        result = retry::send(chat, &callback) => {
+            result?;
+            false
+        }
+        _ = (printer.interrupt)() => true,
+    };
+    let mut stdout = out.into_inner().unwrap();
+
+    if cancelled {
+        let partial = partial.into_inner().unwrap();
+        if partial.is_empty() {
+            return Err(anyhow!("Cancelled"));
+        }
+
+        // Tool calls without all their results would be rejected, drop them
+        let mut history = chat.history().to_vec();
+        while history
+            .last()
+            .is_some_and(|data| data.role.as_deref() == Some("tool") || data.tool_calls.is_some())
+        {
+            history.pop();
+        }
+        history.push(Data::new(Role::Assistant, &partial));
+        chat.restore(history);
+
+        write!(stdout, "{}", markdown.into_inner().unwrap().finish())?;
+        execute!(&mut stdout, style::SetAttribute(style::Attribute::Reset))?;
+        let notice = "Reply cancelled, /continue to keep going";
+        writeln!(stdout, "\n\n{}", notice.yellow())?;
+        return Ok(Ending::Cancelled);
+    }
+
+    let metadata = chat.metadata();
+    if let Some(usage) = metadata.usage {
+        let model = metadata.model.as_deref().unwrap_or(chat.model());
+        let mut footer = format!(
+            "{} + {} tokens",
+            usage.prompt_tokens, usage.completion_tokens
+        );
+        if let Some(cost) = tally.add(model, usage) {
+            footer.push_str(&format!(", {}", pricing::dollars(cost)));
+        }
+        writeln!(stdout, "{}", footer.dim())?;
+    }
+
+    match truncated.into_inner() {
+        true => Ok(Ending::Truncated),
+        false => Ok(Ending::Done),
+    }
+}
+
+/// Send the conversation like `reply`, once fitted in the context window,
+/// then check the reply in JSON mode. An invalid reply is sent back once to
+/// the model together with the errors.
+async fn respond(
+    chat: &mut dyn Conversation,
+    tally: &mut Tally,
+    window: &Window,
+    printer: &mut Printer<'_>,
+) -> Result<Ending> {
+    if let Some(fitted) = window.fit(chat).await? {
+        writeln!(printer.out, "{}", fitted.to_string().dim())?;
+        if let Fitted::Summarized {
+            model,
+            usage: Some(usage),
+            ..
+        } = fitted
+        {
+            tally.add(&model, usage);
+        }
+    }
+
+    let mut ending = reply(chat, tally, printer).await?;
+    if !chat.settings().json || ending == Ending::Cancelled {
+        return Ok(ending);
+    }
+
+    let mut result = schema::check(chat);
+    if let (Err(errors), Ending::Done) = (&result, ending) {
+        let notice = format!("Invalid JSON reply, asking again: {}", errors.join(", "));
+        writeln!(printer.out, "{}", notice.yellow())?;
+
+        chat.build(Role::User, &schema::retry(errors));
+        ending = reply(chat, tally, printer).await?;
+        if ending == Ending::Cancelled {
+            return Ok(ending);
+        }
+        result = schema::check(chat);
+    }
+
+    let stdout = &mut printer.out;
+    match result {
+        Ok(value) => writeln!(stdout, "{}", serde_json::to_string_pretty(&value)?.blue())?,
+        Err(errors) => {
+            let reply = chat.history().last().and_then(|data| data.content.clone());
+            writeln!(stdout, "{}", reply.unwrap_or_default().italic().blue())?;
+            writeln!(stdout, "{}", errors.join("\n").red())?;
+        }
+    }
+
+    Ok(ending)
+}
+
+/// Send a user message like `respond`. When it fails, the conversation is
+/// left as it was before, so that the message can be sent again.
+async fn turn(
+    chat: &mut dyn Conversation,
+    message: &str,
+    tally: &mut Tally,
+    window: &Window,
+    printer: &mut Printer<'_>,
+) -> Result<Ending> {
+    let history = chat.history().to_vec();
+    chat.build(Role::User, message);
+
+    let result = respond(chat, tally, window, printer).await;
+    if result.is_err() {
+        chat.restore(history);
+    }
+    result
+}
+
+/// Send a user message like `turn`, with the files attached to it. When it
+/// fails, they are attached again for the next attempt.
+async fn send(
+    chat: &mut dyn Conversation,
+    context: &mut Context,
+    message: &str,
+    tally: &mut Tally,
+    window: &Window,
+    printer: &mut Printer<'_>,
+) -> Result<Ending> {
+    let attachments = context.attachments().to_vec();
+    let message = match context.take() {
+        Some(files) => format!("{files}\n\n{message}"),
+        None => message.to_string(),
+    };
+
+    let result = turn(chat, &message, tally, window, printer).await;
+    if result.is_err() {
+        for attachment in attachments {
+            context.insert(attachment.path, attachment.content);
+        }
+    }
+    result
+}
+
+fn save(
+    path: &Path,
+    provider: &Provider,
+    created: Option<u64>,
+    chat: &dyn Conversation,
+) -> Result<()> {
+    let mut session = Session::new(provider.name, chat.model(), chat.settings(), chat.history());
+    session.created = created.unwrap_or(session.created);
+    session.save(path)
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let mut output = Output::Text;
+    match run(&mut output).await {
+        Ok(code) => code,
+        Err(error) => {
+            // Scripts asking for JSON get the failure as JSON too
+            match output {
+                Output::Text => eprintln!("Error: {error:?}"),
+                Output::Json | Output::Ndjson => println!("{}", output::error(&error)),
+            }
+
+            // Tell provider errors apart, e.g. to stop retrying on auth ones
+            error
+                .downcast_ref::<OctoError>()
+                .map_or(ExitCode::FAILURE, OctoError::exit_code)
+        }
+    }
+}
+
+/// Run octo, telling `output` the format asked for as soon as it is known.
+async fn run(output: &mut Output) -> Result<ExitCode> {
+    // Command line and environment, on top of the configuration file
+    let matches = Opts::command().get_matches();
+    let cli = Opts::from_arg_matches(&matches)?;
+    let config = Config::load(cli.config.as_deref())?;
+    let mut profile = cli.profile;
+    let mut opts = configure(&matches, &config, profile.as_deref())?;
+    *output = opts.output;
+
+    if opts.print_config {
+        let mut resolved = Profile::from(&opts);
+        if let Ok(provider) = provider::find(&opts.provider) {
+            resolved.url.get_or_insert(provider.url.to_string());
+            if !provider.model.is_empty() {
+                resolved.model.get_or_insert(provider.model.to_string());
+            }
+        }
+        if let Some(name) = &profile {
+            println!("# profile: {name}");
+        }
+        print!("{}", toml::to_string(&resolved)?);
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    // Send a single message rather than starting the REPL
+    let one_shot =
+        opts.prompt.is_some() || !io::stdin().is_terminal() || opts.output != Output::Text;
+
+    let settings = settings(&opts, one_shot)?;
+
+    // Initiate chat completion, or resume the session
+    let mut provider = provider::find(&opts.provider)?;
+    let mut created = None;
+    let mut chat = match opts.session.as_deref().filter(|path| path.exists()) {
+        Some(path) => {
+            let session = Session::load(path)?;
+            created = Some(session.created);
+            let chat;
+            (provider, chat) = resume(session, &opts, &settings, &matches)?;
+            chat
+        }
+        None => provider.connect(
+            opts.api_key.clone(),
+            opts.url.clone(),
+            opts.model.clone(),
+            &settings,
+        )?,
     };
 
+    pick_model(chat.as_mut()).await?;
+
+    // Functions the model can call
+    let mut tools = Tools::new(opts.max_tool_iterations);
+    if opts.tools {
+        workspace::register(&mut tools, &opts.root, Arc::new(Terminal))?;
+    }
+    if let Some(path) = &opts.plugins {
+        plugins::register(&mut tools, path)?;
+    }
+
+    // MCP servers run as long as octo does
+    let mut servers = vec![];
+    for command in &opts.mcp {
+        let server = mcp::Server::spawn(command).await?;
+        for (name, renamed) in mcp::register(&mut tools, &server).await? {
+            eprintln!(
+                "{name} of {} is already a tool, offered as {renamed}",
+                server.name
+            );
+        }
+        servers.push(server);
+    }
+    chat.set_tools(tools.clone())?;
+
+    if let Some(prompt) = system(&opts)? {
+        chat.set_system(&prompt);
+    }
+
+    // Files attached to the next user message
+    let mut context = Context::new(opts.context_budget);
+
+    // Keeps the history within the context window
+    let mut window = Window::new(opts.context_window, opts.truncate);
+
+    if one_shot {
+        // Piped input is the message, or attached to the one given with -p
+        let mut input = String::new();
+        if !io::stdin().is_terminal() {
+            io::stdin().read_to_string(&mut input)?;
+        }
+
+        let message = match &opts.prompt {
+            Some(prompt) => {
+                if !input.trim().is_empty() {
+                    let report = context.insert(PathBuf::from("stdin"), input);
+                    if let Report::Skipped { reason, .. } = report {
+                        return Err(anyhow!("Piped input skipped: {reason}"));
+                    }
+                }
+                match context.take() {
+                    Some(files) => format!("{files}\n\n{prompt}"),
+                    None => prompt.clone(),
+                }
+            }
+            None => input,
+        };
+        if message.trim().is_empty() {
+            return Err(anyhow!(
+                "Nothing to send, pass a prompt with -p or pipe one"
+            ));
+        }
+
+        let outcome = oneshot::run(
+            chat.as_mut(),
+            message.trim(),
+            opts.output,
+            &window,
+            &mut io::stdout(),
+        )
+        .await?;
+        if let Some(path) = &opts.session {
+            save(path, provider, created, chat.as_ref())?;
+        }
+        return Ok(outcome.into());
+    }
+
     // Initialize term instance
     let mut stdout = io::stdout();
 
     // Create a new 'readline' instance
     let mut rl = rustyline::DefaultEditor::new()?;
 
-    // Initiate chat completion
-    let mut chat = match &opts.provider {
-        Provider::OpenAI => chat::Chat::new(
-            &opts.api_key.unwrap_or(std::env::var("OPENAI_API_KEY")?),
-            &opts
-                .url
-                .unwrap_or("https://api.openai.com/v1/chat/completions".to_string()),
-            &opts.model.unwrap_or("gpt-3.5-turbo-1106".to_string()),
-            &settings,
-        ),
-        Provider::TogetherAI => chat::Chat::new(
-            &opts.api_key.unwrap_or(std::env::var("TOGETHERAI_API_KEY")?),
-            &opts
-                .url
-                .unwrap_or("https://api.together.xyz/v1/chat/completions".to_string()),
-            &opts
-                .model
-                .unwrap_or("mistralai/Mixtral-8x7B-Instruct-v0.1".to_string()),
-            &settings,
-        ),
-        Provider::MistralAI => chat::Chat::new(
-            &opts.api_key.unwrap_or(std::env::var("MISTRALAI_API_KEY")?),
-            &opts
-                .url
-                .unwrap_or("https://api.mistral.ai/v1/chat/completions".to_string()),
-            &opts.model.unwrap_or("mistral-medium".to_string()),
-            &settings,
-        ),
-        Provider::Gemini => Err(anyhow!("Gemini provider not implemented yet!"))?,
-    };
+    // Whether the last reply was cut short, and can be continued
+    let mut truncated = false;
+
+    // Input which failed to be sent, offered again at the prompt
+    let mut failed: Option<String> = None;
+
+    // Tokens used, and what they cost
+    let mut tally = Tally::new(Prices::load(opts.prices.as_deref())?);
+
+    // Toggled with /raw, kept when switching profiles
+    let mut raw = opts.raw;
+
+    // The provider the API key and URL on the command line are meant for
+    let started = opts.provider.clone();
 
     writeln!(
         stdout,
@@ -135,55 +977,273 @@ async fn main() -> Result<()> {
             cursor::EnableBlinking
         )?;
 
+        // Show how much of the context window the conversation uses
+        let gauge = match window.usage(chat.as_ref()) {
+            (0, _) => String::new(),
+            (used, Some(size)) => format!("[{}/{}] ", window::short(used), window::short(size)),
+            (used, None) => format!("[{}] ", window::short(used)),
+        };
+
         // FIXME - Add auto corrector
-        let input = rl.readline("\n")?.trim().to_string().to_owned();
-
-        if input == "/exit" || input == "/quit" {
-            break;
-        } else {
-            writeln!(stdout)?;
-            execute!(stdout, cursor::SavePosition)?;
-
-            // FIXME - Using animated waiting
-            writeln!(stdout, "{}", "Thinking...".italic().blue())?;
-
-            // We are not handling errors, instead we are just bubbling them up.
-            // Therefore, anything caught after this point will be printed in
-            // whatever style we set here.
-            // Assume the worst, prepare the terminal style for errors.
-            execute!(
-                stdout,
-                style::SetAttribute(style::Attribute::Bold),
-                style::SetForegroundColor(style::Color::Red)
-            )?;
-
-            chat.build(Role::User, &input)
-                .send(|state| {
-                    match state {
-                        State::Start => {
-                            // No errors; reset the terminal style to print out the response message
-                            execute!(
-                                &stdout,
-                                cursor::RestorePosition,
-                                terminal::Clear(terminal::ClearType::FromCursorDown),
-                                style::SetAttribute(style::Attribute::Reset)
-                            )
-                            .unwrap();
+        let initial = failed.take().unwrap_or_default();
+        let input = match rl.readline_with_initial(&format!("\n{gauge}"), (&initial, "")) {
+            Ok(input) => input.trim().to_string(),
+            // Ctrl-C clears the line, Ctrl-D exits
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(error) => return Err(error.into()),
+        };
+
+        if input.is_empty() {
+            continue;
+        }
+
+        let cmd = match Cmd::parse(&input) {
+            Ok(cmd) => cmd,
+            Err(error) => {
+                writeln!(stdout, "{}", error.to_string().red())?;
+                continue;
+            }
+        };
+
+        match cmd {
+            Cmd::Exit => break,
+            Cmd::Help => {
+                write!(stdout, "{}", command::help().dim())?;
+            }
+            Cmd::User(message) => {
+                let mut printer = Printer::new(&mut stdout, raw);
+                let sent = send(
+                    chat.as_mut(),
+                    &mut context,
+                    &message,
+                    &mut tally,
+                    &window,
+                    &mut printer,
+                )
+                .await;
+                match sent {
+                    Ok(ending) => truncated = ending != Ending::Done,
+                    Err(error) => {
+                        failure(&mut stdout, &error)?;
+                        failed = Some(input);
+                    }
+                }
+            }
+            Cmd::Continue => {
+                if !truncated {
+                    writeln!(stdout, "{}", "The last reply was not truncated".dim())?;
+                    continue;
+                }
+                let mut printer = Printer::new(&mut stdout, raw);
+                match turn(chat.as_mut(), CONTINUE, &mut tally, &window, &mut printer).await {
+                    Ok(ending) => truncated = ending != Ending::Done,
+                    Err(error) => {
+                        failure(&mut stdout, &error)?;
+                        failed = Some(input);
+                    }
+                }
+            }
+            Cmd::System(Some(prompt)) => {
+                chat.set_system(&prompt);
+            }
+            Cmd::System(None) => match chat.system() {
+                Some(prompt) => writeln!(stdout, "{}", prompt.dim())?,
+                None => writeln!(stdout, "{}", "No system prompt set".dim())?,
+            },
+            Cmd::Context(ContextCmd::Add(references)) => {
+                for reference in references {
+                    if reference.contains("://") {
+                        let uri = reference.trim_start_matches('@');
+                        match mcp::read(&servers, uri).await {
+                            Ok(text) => {
+                                let report = context.insert(PathBuf::from(uri), text);
+                                writeln!(stdout, "{}", report.to_string().dim())?;
+                            }
+                            Err(error) => writeln!(stdout, "{}", error.to_string().red())?,
                         }
-                        State::Message(text) => {
-                            // Append text response
-                            write!(&stdout, "{}", text.as_str().italic().blue()).unwrap();
+                        continue;
+                    }
 
-                            // Flush stdout after each chunk for a typewriter effect
-                            io::stdout().flush().unwrap();
+                    match context.attach(&reference) {
+                        Ok(reports) => {
+                            for report in reports {
+                                writeln!(stdout, "{}", report.to_string().dim())?;
+                            }
                         }
-                        State::Stop | State::Done => {
-                            writeln!(&stdout).unwrap();
+                        Err(error) => writeln!(stdout, "{}", error.to_string().red())?,
+                    }
+                }
+            }
+            Cmd::Context(ContextCmd::List) => {
+                for attachment in context.attachments() {
+                    writeln!(
+                        stdout,
+                        "{}",
+                        format!(
+                            "{} ({}, ~{} tokens)",
+                            attachment.path.display(),
+                            context::size(attachment.content.len()),
+                            attachment.tokens()
+                        )
+                        .dim()
+                    )?;
+                }
+                writeln!(
+                    stdout,
+                    "{}",
+                    format!(
+                        "{} used of the {} budget",
+                        context::size(context.bytes()),
+                        context.budget()
+                    )
+                    .dim()
+                )?;
+            }
+            Cmd::Context(ContextCmd::Drop(path)) => {
+                let count = context.detach(&path);
+                writeln!(stdout, "{}", format!("Dropped {count} file(s)").dim())?;
+            }
+            Cmd::Context(ContextCmd::Resources) => {
+                if servers.is_empty() {
+                    writeln!(stdout, "{}", "No MCP server, start one with --mcp".dim())?;
+                }
+                for server in &servers {
+                    match server.resources().await {
+                        Ok(resources) => {
+                            for resource in resources {
+                                let line = format!(
+                                    "{} ({}, {})",
+                                    resource.uri, resource.name, server.name
+                                );
+                                writeln!(stdout, "{}", line.dim())?;
+                            }
                         }
-                        _ => {}
+                        Err(error) => writeln!(stdout, "{}", error.to_string().red())?,
+                    }
+                }
+            }
+            Cmd::Models => match chat.models().await {
+                Ok(models) => {
+                    for model in models {
+                        let current = if model == chat.model() { " *" } else { "" };
+                        writeln!(stdout, "{}", format!("{model}{current}").dim())?;
+                    }
+                }
+                Err(error) => writeln!(stdout, "{}", error.to_string().red())?,
+            },
+            Cmd::Json(Some(on)) => {
+                let settings = chat.settings_mut();
+                settings.json = on;
+                if !on {
+                    settings.schema = None;
+                }
+            }
+            Cmd::Json(None) => {
+                let settings = chat.settings();
+                let state = match (settings.json, &settings.schema) {
+                    (true, Some(_)) => "JSON mode is on, with a schema",
+                    (true, None) => "JSON mode is on",
+                    (false, _) => "JSON mode is off",
+                };
+                writeln!(stdout, "{}", state.dim())?;
+            }
+            Cmd::Raw(toggled) => {
+                raw = toggled.unwrap_or(!raw);
+                let state = match raw {
+                    true => "Raw mode is on, replies are printed as they come",
+                    false => "Raw mode is off, replies are rendered as Markdown",
+                };
+                writeln!(stdout, "{}", state.dim())?;
+            }
+            Cmd::Profile(Some(name)) => {
+                let switched = match reconfigure(&matches, &config, &name, &opts, &started) {
+                    Ok(switched) => switch(switched, chat.as_ref(), &tools).await,
+                    Err(error) => Err(error),
+                };
+                match switched {
+                    Ok((switched_opts, switched_provider, switched_chat)) => {
+                        (opts, provider, chat) = (switched_opts, switched_provider, switched_chat);
+                        window = Window::new(opts.context_window, opts.truncate);
+                        let line = format!("Using {} with {}", provider.name, chat.model());
+                        writeln!(stdout, "{}", line.dim())?;
+                        profile = Some(name);
                     }
-                })
-                .await?;
+                    Err(error) => writeln!(stdout, "{}", error.to_string().red())?,
+                }
+            }
+            Cmd::Profile(None) => {
+                if config.profile.is_empty() {
+                    writeln!(stdout, "{}", "No profile configured".dim())?;
+                }
+                for name in config.profile.keys() {
+                    let current = if Some(name) == profile.as_ref() {
+                        " *"
+                    } else {
+                        ""
+                    };
+                    writeln!(stdout, "{}", format!("{name}{current}").dim())?;
+                }
+            }
+            Cmd::Usage => {
+                for (model, usage, cost) in tally.iter() {
+                    let cost = cost.map(pricing::dollars).unwrap_or("unknown price".into());
+                    let line = format!(
+                        "{model}: {} + {} tokens, {cost}",
+                        usage.prompt_tokens, usage.completion_tokens
+                    );
+                    writeln!(stdout, "{}", line.dim())?;
+                }
+
+                let total = tally.total();
+                let (cost, unknown) = tally.cost();
+                let mut line = format!(
+                    "Total: {} tokens, {} estimated",
+                    total.total_tokens,
+                    pricing::dollars(cost)
+                );
+                if unknown {
+                    line.push_str(", some models have no known price");
+                }
+                writeln!(stdout, "{}", line.dim())?;
+            }
+            Cmd::Save(path) => {
+                let Some(path) = path.or(opts.session.clone()) else {
+                    writeln!(stdout, "{}", "Usage: /save <file>".red())?;
+                    continue;
+                };
+                match save(&path, provider, created, chat.as_ref()) {
+                    Ok(()) => writeln!(stdout, "{}", format!("Saved {}", path.display()).dim())?,
+                    Err(error) => writeln!(stdout, "{}", error.to_string().red())?,
+                }
+            }
+            Cmd::Load(path) => match Session::load(&path).and_then(|session| {
+                let created = session.created;
+                let cli = crate::settings(&opts, false)?;
+                resume(session, &opts, &cli, &matches).map(|resumed| (resumed, created))
+            }) {
+                Ok(((resumed_provider, mut resumed_chat), resumed_created)) => {
+                    let picked = pick_model(resumed_chat.as_mut()).await;
+                    if let Err(error) = picked.and_then(|_| resumed_chat.set_tools(tools.clone())) {
+                        writeln!(stdout, "{}", error.to_string().red())?;
+                        continue;
+                    }
+                    (provider, chat) = (resumed_provider, resumed_chat);
+                    created = Some(resumed_created);
+                    context = Context::new(opts.context_budget);
+                    writeln!(
+                        stdout,
+                        "{}",
+                        format!(
+                            "Loaded {} messages from {}",
+                            chat.history().len(),
+                            path.display()
+                        )
+                        .dim()
+                    )?;
+                }
+                Err(error) => writeln!(stdout, "{}", error.to_string().red())?,
+            },
         }
     }
 
@@ -194,5 +1254,272 @@ async fn main() -> Result<()> {
         cursor::Show
     )?;
 
-    Ok(())
+    Ok(ExitCode::SUCCESS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+    use wiremock::{matchers::method, Mock, MockServer, ResponseTemplate};
+
+    use crate::chat::{Chat, Settings};
+
+    #[test]
+    fn test_resume_settings() {
+        let matches =
+            Opts::command().get_matches_from(["octo", "--api-key", "key", "--temperature", "0.1"]);
+        let opts = Opts::from_arg_matches(&matches).unwrap();
+        let cli = settings(&opts, false).unwrap();
+
+        let saved = chat::Settings {
+            temperature: 0.5,
+            top_p: 0.3,
+            ..Default::default()
+        };
+        let session = Session::new("open-ai", "gpt-4", &saved, &[]);
+
+        // Explicit options win over the saved settings, which win over defaults
+        let (_, chat) = resume(session, &opts, &cli, &matches).unwrap();
+        assert_eq!(chat.settings().temperature, 0.1);
+        assert_eq!(chat.settings().top_p, 0.3);
+        assert_eq!(chat.model(), "gpt-4");
+    }
+
+    #[test]
+    fn test_switch_profiles() {
+        let config: Config = toml::from_str(
+            r#"
+            [profile.google]
+            provider = "gemini"
+
+            [profile.work]
+            temperature = 0.2
+
+            [profile.elsewhere]
+            root = "/tmp"
+            mcp = ["server"]
+            "#,
+        )
+        .unwrap();
+        let matches = Opts::command().get_matches_from([
+            "octo",
+            "--api-key",
+            "sk-openai",
+            "--url",
+            "https://openai.example",
+        ]);
+        let current = configure(&matches, &config, None).unwrap();
+
+        // The key and URL are not sent to another provider
+        let opts = reconfigure(&matches, &config, "google", &current, "open-ai").unwrap();
+        assert_eq!(opts.provider, "gemini");
+        assert_eq!((opts.api_key, opts.url), (None, None));
+
+        let opts = reconfigure(&matches, &config, "work", &current, "open-ai").unwrap();
+        assert_eq!(opts.api_key.as_deref(), Some("sk-openai"));
+        assert_eq!(opts.url.as_deref(), Some("https://openai.example"));
+        assert_eq!(opts.temperature, 0.2);
+
+        let error = reconfigure(&matches, &config, "elsewhere", &current, "open-ai").unwrap_err();
+        assert!(error.to_string().contains("sets root, mcp"), "{error}");
+    }
+
+    fn chat(url: &str) -> Chat {
+        let settings = Settings {
+            stream: true,
+            ..Default::default()
+        };
+        let mut chat = Chat::new("key", url, "model", &settings).unwrap();
+        chat.build(Role::User, "Hi").build(Role::Assistant, "Hello");
+        chat
+    }
+
+    /// Stands for Ctrl-C, pressed shortly after sending.
+    fn soon() -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        Box::pin(tokio::time::sleep(Duration::from_millis(300)))
+    }
+
+    /// A server sending the first chunk of a reply, then nothing more.
+    async fn stalled() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = vec![0; 64 * 1024];
+            let _ = socket.read(&mut request).await;
+
+            let chunk = include_str!("../tests/fixtures/openai/answer.sse")
+                .split("\n\n")
+                .next()
+                .unwrap();
+            let chunk = format!("{chunk}\n\n");
+            let response = format!(
+                "HTTP/1.1 200 OK\r\ncontent-type: text/event-stream\r\n\
+                transfer-encoding: chunked\r\n\r\n{:x}\r\n{chunk}\r\n",
+                chunk.len()
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+            std::future::pending::<()>().await;
+        });
+        format!("http://{address}")
+    }
+
+    #[tokio::test]
+    async fn test_failed_turn() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(400).set_body_raw(
+                include_str!("../tests/fixtures/openai/context_length.json"),
+                "application/json",
+            ))
+            .mount(&server)
+            .await;
+
+        let mut chat = chat(&server.uri());
+        let mut context = Context::new(Budget::Bytes(1024));
+        context.insert(PathBuf::from("notes.txt"), "Buy milk".to_string());
+        let (mut tally, mut out) = (Tally::new(Prices::default()), vec![]);
+
+        let mut printer = Printer::new(&mut out, true);
+        let error = send(
+            &mut chat,
+            &mut context,
+            "What did I note?",
+            &mut tally,
+            &Window::default(),
+            &mut printer,
+        )
+        .await
+        .unwrap_err();
+        assert!(error.downcast_ref::<OctoError>().is_some(), "{error}");
+
+        // The files went with the message
+        let requests = server.received_requests().await.unwrap();
+        let body = String::from_utf8_lossy(&requests[0].body);
+        assert!(body.contains("Buy milk"), "{body}");
+
+        // Left as it was, to send again
+        assert_eq!(chat.history().len(), 2);
+        let attached = context
+            .attachments()
+            .iter()
+            .map(|attachment| attachment.path.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(attached, [PathBuf::from("notes.txt")]);
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_reply() {
+        let mut chat = chat(&stalled().await);
+        let (mut tally, mut out) = (Tally::new(Prices::default()), vec![]);
+        let mut printer = Printer {
+            out: &mut out,
+            raw: true,
+            interrupt: soon,
+        };
+
+        // What came of the reply is kept
+        let ending = turn(
+            &mut chat,
+            "What is 1 + 2?",
+            &mut tally,
+            &Window::default(),
+            &mut printer,
+        )
+        .await
+        .unwrap();
+        assert_eq!(ending, Ending::Cancelled);
+        let last = chat.history().last().unwrap();
+        assert_eq!(last.role.as_deref(), Some("assistant"));
+        assert_eq!(last.content.as_deref(), Some("It is 3"));
+        assert!(String::from_utf8_lossy(&out).contains("Reply cancelled"));
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_retry() {
+        // Sends the first chunk of a reply, then drops the connection
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = vec![0; 64 * 1024];
+            let _ = socket.read(&mut request).await;
+
+            let chunk = "data: {\"choices\":[{\"delta\":{\"content\":\"```rust\\nlet\"}}]}\n\n";
+            let response = format!(
+                "HTTP/1.1 200 OK\r\ncontent-type: text/event-stream\r\n\
+                transfer-encoding: chunked\r\n\r\n{:x}\r\n{chunk}\r\n",
+                chunk.len()
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+        });
+
+        let mut chat = chat(&format!("http://{address}"));
+        chat.settings_mut().retry = Retry {
+            delay: Duration::from_secs(10),
+            partial: true,
+            ..Default::default()
+        };
+        let (mut tally, mut out) = (Tally::new(Prices::default()), vec![]);
+        let mut printer = Printer {
+            out: &mut out,
+            raw: false,
+            interrupt: soon,
+        };
+
+        // Cancelled while waiting to retry, the failed attempt is not a reply
+        let error = turn(
+            &mut chat,
+            "Show me some Rust",
+            &mut tally,
+            &Window::default(),
+            &mut printer,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.to_string(), "Cancelled");
+        assert_eq!(chat.history().len(), 2);
+
+        // Nothing of it is rendered after the notice
+        let out = String::from_utf8_lossy(&out);
+        let (_, after) = out.rsplit_once("retrying in").unwrap();
+        assert!(!after.contains("let"), "{after}");
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_turn() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(10)))
+            .mount(&server)
+            .await;
+
+        let mut chat = chat(&server.uri());
+        let (mut tally, mut out) = (Tally::new(Prices::default()), vec![]);
+        let mut printer = Printer {
+            out: &mut out,
+            raw: true,
+            interrupt: soon,
+        };
+
+        // Nothing came, the message is taken back
+        let error = turn(
+            &mut chat,
+            "Are you there?",
+            &mut tally,
+            &Window::default(),
+            &mut printer,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.to_string(), "Cancelled");
+        assert_eq!(chat.history().len(), 2);
+    }
 }
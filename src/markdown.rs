@@ -0,0 +1,522 @@
+use std::{mem, sync::OnceLock};
+
+use crossterm::{
+    cursor,
+    style::{ContentStyle, Stylize},
+    terminal, Command,
+};
+use pulldown_cmark::{Event, LinkType, Options, Parser, Tag};
+use syntect::{
+    easy::HighlightLines,
+    highlighting::{Theme, ThemeSet},
+    parsing::SyntaxSet,
+    util::as_24_bit_terminal_escaped,
+};
+use textwrap::core::display_width;
+
+/// Theme of the code blocks, one of the syntect defaults.
+const THEME: &str = "base16-ocean.dark";
+
+fn syntaxes() -> &'static SyntaxSet {
+    static SYNTAXES: OnceLock<SyntaxSet> = OnceLock::new();
+    SYNTAXES.get_or_init(SyntaxSet::load_defaults_newlines)
+}
+
+fn theme() -> &'static Theme {
+    static THEME_: OnceLock<Theme> = OnceLock::new();
+    THEME_.get_or_init(|| {
+        ThemeSet::load_defaults()
+            .themes
+            .remove(THEME)
+            .unwrap_or_default()
+    })
+}
+
+/// A fenced code block being received.
+struct Fence {
+    /// The backticks or tildes which opened the block, and close it.
+    marker: String,
+
+    /// Highlights the lines, when the language is known.
+    highlighter: Option<HighlightLines<'static>>,
+}
+
+/// The fence opening a code block, a run of at least three backticks or
+/// tildes.
+fn fence(line: &str) -> Option<String> {
+    let first = line.chars().next().filter(|c| matches!(c, '`' | '~'))?;
+    let marker = line.chars().take_while(|c| *c == first).collect::<String>();
+    (marker.len() >= 3).then_some(marker)
+}
+
+/// Renders a reply as Markdown for the terminal, as it streams in.
+///
+/// Lines are rendered once complete. Until then the partial line is shown
+/// as is, then erased and drawn again. Code blocks are highlighted line by
+/// line, while tables are held back until their last row, to align the
+/// columns.
+pub struct Markdown {
+    /// Columns of the terminal.
+    width: usize,
+
+    /// The line being received.
+    line: String,
+
+    /// Columns taken by the partial line shown so far.
+    shown: usize,
+
+    fence: Option<Fence>,
+    table: Vec<String>,
+}
+
+impl Markdown {
+    pub fn new(width: usize) -> Self {
+        Markdown {
+            width: width.max(1),
+            line: String::new(),
+            shown: 0,
+            fence: None,
+            table: vec![],
+        }
+    }
+
+    /// Add a chunk of the reply, returning what to print.
+    pub fn push(&mut self, chunk: &str) -> String {
+        let mut out = String::new();
+        for piece in chunk.split_inclusive('\n') {
+            match piece.strip_suffix('\n') {
+                Some(end) => {
+                    self.line.push_str(end);
+                    let line = mem::take(&mut self.line);
+                    self.erase(&mut out);
+                    self.render(line.trim_end_matches('\r'), &mut out);
+                }
+                None => {
+                    self.line.push_str(piece);
+
+                    // Table rows only show once the table is complete
+                    if !self.line.trim_start().starts_with('|') {
+                        out.push_str(piece);
+                        self.shown += display_width(piece);
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    /// Render what is left once the reply is done, without a final newline.
+    pub fn finish(&mut self) -> String {
+        let mut out = String::new();
+        let line = mem::take(&mut self.line);
+        self.erase(&mut out);
+        if !line.is_empty() {
+            self.render(&line, &mut out);
+        }
+        self.table(&mut out);
+        self.fence = None;
+
+        if out.ends_with('\n') {
+            out.pop();
+        }
+        out
+    }
+
+    /// Erase the partial line, which may wrap over a few rows.
+    fn erase(&mut self, out: &mut String) {
+        if self.shown == 0 {
+            return;
+        }
+
+        let rows = (self.shown - 1) / self.width;
+        out.push('\r');
+        if rows > 0 {
+            let _ = cursor::MoveUp(rows.min(u16::MAX as usize) as u16).write_ansi(out);
+        }
+        let _ = terminal::Clear(terminal::ClearType::FromCursorDown).write_ansi(out);
+        self.shown = 0;
+    }
+
+    /// Render a complete line.
+    fn render(&mut self, line: &str, out: &mut String) {
+        let trimmed = line.trim_start();
+
+        if let Some(fence) = &mut self.fence {
+            let closing = trimmed.trim_end();
+            if closing.starts_with(&fence.marker)
+                && closing.chars().all(|c| fence.marker.starts_with(c))
+            {
+                self.fence = None;
+                out.push_str(&format!("{}\n", line.dim()));
+                return;
+            }
+
+            let text = format!("{line}\n");
+            let highlighted = fence.highlighter.as_mut().and_then(|highlighter| {
+                let ranges = highlighter.highlight_line(&text, syntaxes()).ok()?;
+                Some(as_24_bit_terminal_escaped(&ranges, false))
+            });
+            match highlighted {
+                Some(escaped) => {
+                    out.push_str(escaped.trim_end_matches('\n'));
+                    out.push_str("\x1b[0m\n");
+                }
+                None => out.push_str(&text),
+            }
+            return;
+        }
+
+        if trimmed.starts_with('|') {
+            self.table.push(line.to_string());
+            return;
+        }
+        self.table(out);
+
+        if let Some(marker) = fence(trimmed) {
+            let lang = trimmed[marker.len()..]
+                .split_whitespace()
+                .next()
+                .unwrap_or("");
+            let highlighter = syntaxes()
+                .find_syntax_by_token(lang)
+                .map(|syntax| HighlightLines::new(syntax, theme()));
+            self.fence = Some(Fence {
+                marker,
+                highlighter,
+            });
+            out.push_str(&format!("{}\n", line.dim()));
+            return;
+        }
+
+        if trimmed.is_empty() {
+            out.push('\n');
+            return;
+        }
+
+        // Thematic breaks, e.g. `---` or `* * *`
+        let marks = trimmed
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .collect::<String>();
+        if marks.len() >= 3
+            && ["-", "*", "_"]
+                .iter()
+                .any(|c| marks.replace(c, "").is_empty())
+        {
+            out.push_str(&format!("{}\n", "─".repeat(self.width).dim()));
+            return;
+        }
+
+        let level = trimmed.chars().take_while(|c| *c == '#').count();
+        if (1..=6).contains(&level)
+            && trimmed[level..]
+                .chars()
+                .next()
+                .is_none_or(char::is_whitespace)
+        {
+            let text = trimmed[level..].trim().trim_end_matches('#').trim_end();
+            let style = match level {
+                1 => ContentStyle::new().bold().underlined().magenta(),
+                2 => ContentStyle::new().bold().magenta(),
+                _ => ContentStyle::new().bold(),
+            };
+            out.push_str(&self.wrap(&inline(text, style), "", ""));
+            return;
+        }
+
+        if trimmed.starts_with('>') {
+            let mut depth = 0;
+            let mut text = trimmed;
+            while let Some(rest) = text.strip_prefix('>') {
+                depth += 1;
+                text = rest.strip_prefix(' ').unwrap_or(rest).trim_start();
+            }
+            let prefix = format!("{}", "│ ".repeat(depth).dim());
+            let style = ContentStyle::new().italic();
+            out.push_str(&self.wrap(&inline(text, style), &prefix, &prefix));
+            return;
+        }
+
+        let indent = " ".repeat(line.len() - trimmed.len());
+        if let Some((bullet, text)) = item(trimmed) {
+            let initial = format!("{indent}{bullet} ");
+            let subsequent = " ".repeat(display_width(&initial));
+            let text = inline(text, ContentStyle::new());
+            out.push_str(&self.wrap(&text, &initial, &subsequent));
+            return;
+        }
+
+        out.push_str(&self.wrap(&inline(trimmed, ContentStyle::new()), &indent, &indent));
+    }
+
+    /// Wrap a rendered line at the width of the terminal.
+    fn wrap(&self, text: &str, initial: &str, subsequent: &str) -> String {
+        // Breaking words could split the escape sequences
+        let options = textwrap::Options::new(self.width)
+            .initial_indent(initial)
+            .subsequent_indent(subsequent)
+            .break_words(false);
+        let mut lines = textwrap::wrap(text, options).join("\n");
+        lines.push('\n');
+        lines
+    }
+
+    /// Render the table held back, with aligned columns shrunk to fit the
+    /// terminal if need be.
+    fn table(&mut self, out: &mut String) {
+        if self.table.is_empty() {
+            return;
+        }
+        let lines = mem::take(&mut self.table);
+        let rows = lines.iter().map(|line| cells(line)).collect::<Vec<_>>();
+
+        // Without the delimiter row, it is not a table
+        let delimiter = rows.get(1).filter(|cells| {
+            !cells.is_empty()
+                && cells.iter().all(|cell| {
+                    let dashes = cell.trim_start_matches(':').trim_end_matches(':');
+                    !dashes.is_empty() && dashes.chars().all(|c| c == '-')
+                })
+        });
+        let Some(delimiter) = delimiter else {
+            for line in lines {
+                out.push_str(&self.wrap(&inline(line.trim(), ContentStyle::new()), "", ""));
+            }
+            return;
+        };
+
+        let columns = rows.iter().map(Vec::len).max().unwrap_or(0);
+        let aligns = (0..columns)
+            .map(|column| {
+                let cell = delimiter.get(column).map_or("", String::as_str);
+                (cell.starts_with(':'), cell.ends_with(':'))
+            })
+            .collect::<Vec<_>>();
+
+        let rendered = rows
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| *index != 1)
+            .map(|(index, cells)| {
+                let style = match index {
+                    0 => ContentStyle::new().bold(),
+                    _ => ContentStyle::new(),
+                };
+                (0..columns)
+                    .map(|column| inline(cells.get(column).map_or("", String::as_str), style))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        let mut widths = (0..columns)
+            .map(|column| {
+                let width = rendered.iter().map(|cells| display_width(&cells[column]));
+                width.max().unwrap_or(0).max(1)
+            })
+            .collect::<Vec<_>>();
+
+        // Shrink the widest columns until the table fits
+        let available = self.width.saturating_sub(3 * columns.saturating_sub(1));
+        while widths.iter().sum::<usize>() > available {
+            let Some(widest) = (0..columns).max_by_key(|column| widths[*column]) else {
+                break;
+            };
+            if widths[widest] <= 3 {
+                break;
+            }
+            widths[widest] -= 1;
+        }
+
+        let separator = format!("{}", " │ ".dim());
+        for (index, cells) in rendered.iter().enumerate() {
+            let wrapped = cells
+                .iter()
+                .zip(&widths)
+                .map(|(cell, width)| {
+                    let options = textwrap::Options::new(*width).break_words(false);
+                    textwrap::wrap(cell, options)
+                        .into_iter()
+                        .map(|line| line.into_owned())
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>();
+            let height = wrapped.iter().map(Vec::len).max().unwrap_or(1).max(1);
+
+            for row in 0..height {
+                let line = wrapped
+                    .iter()
+                    .zip(&widths)
+                    .zip(&aligns)
+                    .map(|((lines, width), align)| {
+                        let text = lines.get(row).map_or("", String::as_str);
+                        let pad = width.saturating_sub(display_width(text));
+                        let (left, right) = match align {
+                            (true, true) => (pad / 2, pad - pad / 2),
+                            (false, true) => (pad, 0),
+                            _ => (0, pad),
+                        };
+                        format!("{}{text}{}", " ".repeat(left), " ".repeat(right))
+                    })
+                    .collect::<Vec<_>>();
+                out.push_str(line.join(&separator).trim_end());
+                out.push('\n');
+            }
+
+            if index == 0 {
+                let rule = widths
+                    .iter()
+                    .map(|width| "─".repeat(*width))
+                    .collect::<Vec<_>>();
+                out.push_str(&format!("{}\n", rule.join("─┼─").dim()));
+            }
+        }
+    }
+}
+
+/// The bullet and the text of a list item, the bullet being a number for
+/// ordered lists.
+fn item(line: &str) -> Option<(String, &str)> {
+    let (bullet, text) = match line.strip_prefix(['-', '*', '+']) {
+        Some(text) => ("•".to_string(), text),
+        None => {
+            let digits = line.chars().take_while(char::is_ascii_digit).count();
+            let rest = line[digits..]
+                .strip_prefix(['.', ')'])
+                .filter(|_| (1..10).contains(&digits))?;
+            (line[..digits + 1].to_string(), rest)
+        }
+    };
+    let text = text.strip_prefix(' ')?.trim_start();
+
+    Some(match text.get(..4) {
+        Some("[ ] ") => (format!("{bullet} ☐"), &text[4..]),
+        Some("[x] " | "[X] ") => (format!("{bullet} ☑"), &text[4..]),
+        _ => (bullet, text),
+    })
+}
+
+/// The cells of a table row.
+fn cells(line: &str) -> Vec<String> {
+    let line = line.trim();
+    let line = line.strip_prefix('|').unwrap_or(line);
+    let line = line.strip_suffix('|').unwrap_or(line);
+    line.split('|')
+        .map(|cell| cell.trim().to_string())
+        .collect()
+}
+
+/// Render the emphasis, code spans and links of a line of text, in the
+/// given style.
+fn inline(text: &str, base: ContentStyle) -> String {
+    let mut out = String::new();
+    let mut styles = vec![base];
+    let mut links: Vec<Option<String>> = vec![];
+    let mut list = None;
+
+    for event in Parser::new_ext(text, Options::ENABLE_STRIKETHROUGH) {
+        let style = *styles.last().unwrap_or(&base);
+        match event {
+            Event::Start(Tag::Emphasis) => styles.push(style.italic()),
+            Event::Start(Tag::Strong) => styles.push(style.bold()),
+            Event::Start(Tag::Strikethrough) => styles.push(style.crossed_out()),
+            Event::Start(Tag::Link(kind, url, _)) => {
+                styles.push(style.underlined().blue());
+                let shown = matches!(kind, LinkType::Autolink | LinkType::Email);
+                links.push((!shown && !url.is_empty()).then(|| url.to_string()));
+            }
+            Event::End(Tag::Emphasis | Tag::Strong | Tag::Strikethrough) => {
+                styles.pop();
+            }
+            Event::End(Tag::Link(..)) => {
+                styles.pop();
+                if let Some(url) = links.pop().flatten() {
+                    out.push_str(&format!("{}", format!(" ({url})").dim()));
+                }
+            }
+            Event::Text(text) => out.push_str(&format!("{}", style.apply(text))),
+            Event::Code(code) => out.push_str(&format!("{}", style.yellow().apply(code))),
+            Event::Html(html) => out.push_str(&html),
+            Event::SoftBreak | Event::HardBreak => out.push(' '),
+
+            // Block markers left in the text, e.g. `2024. A year` read as
+            // an ordered list, are kept as written
+            Event::Start(Tag::List(first)) => list = Some(first),
+            Event::Start(Tag::Item) => match list {
+                Some(Some(number)) => out.push_str(&format!("{number}. ")),
+                _ => out.push_str("- "),
+            },
+            Event::Start(Tag::Heading(level, ..)) => {
+                out.push_str(&format!("{} ", "#".repeat(level as usize)));
+            }
+            Event::Start(Tag::BlockQuote) => out.push_str("> "),
+            Event::Rule => out.push_str("---"),
+            Event::FootnoteReference(name) => out.push_str(&format!("[^{name}]")),
+            _ => {}
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use regex::Regex;
+
+    /// The text of the output, without its escape sequences.
+    fn plain(output: &str) -> String {
+        let escapes = Regex::new(r"\x1b\[[0-9;?]*[A-Za-z]|\r").unwrap();
+        escapes.replace_all(output, "").into_owned()
+    }
+
+    #[test]
+    fn test_render() {
+        let reply = "# Title\n\
+            Some *emphasis* and `code`, see [docs](https://docs.rs).\n\
+            \n\
+            - one\n  2. two\n\
+            > quoted\n\
+            \n\
+            | Name | Size |\n|:-----|-----:|\n| a | 1 |\n| bb | 10 |\n\
+            ```rust\nfn main() {}\n```\n\
+            ---\n";
+
+        let mut markdown = Markdown::new(40);
+        let mut output = markdown.push(reply);
+        output.push_str(&markdown.finish());
+
+        assert_eq!(
+            plain(&output),
+            "Title\n\
+             Some emphasis and code, see docs\n\
+             (https://docs.rs).\n\
+             \n\
+             • one\n  2. two\n\
+             │ quoted\n\
+             \n\
+             Name │ Size\n\
+             ─────┼─────\n\
+             a    │    1\n\
+             bb   │   10\n\
+             ```rust\nfn main() {}\n```\n\
+             ────────────────────────────────────────\n"
+        );
+        assert!(output.contains("\x1b[38;2;"), "code is highlighted");
+    }
+
+    #[test]
+    fn test_partial_lines() {
+        let mut markdown = Markdown::new(10);
+
+        // Shown as is, then drawn again once complete, from the first of
+        // the two rows it took
+        assert_eq!(markdown.push("**Hello wor"), "**Hello wor");
+        let output = markdown.push("ld**\n| a |");
+        assert!(output.starts_with("\r\x1b[1A\x1b[J"), "{output:?}");
+        assert_eq!(plain(&output), "Hello\nworld\n");
+
+        // Table rows wait for the end of the table
+        assert_eq!(markdown.push(" b |\n"), "");
+        assert_eq!(plain(&markdown.finish()), "| a | b |");
+    }
+}
@@ -0,0 +1,527 @@
+use std::{io, process::Stdio, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, Lines},
+    process::{Child, Command},
+    sync::Mutex,
+};
+
+use crate::tools::{Handler, Tools};
+
+/// The protocol revision announced during the handshake.
+const PROTOCOL_VERSION: &str = "2024-11-05";
+
+/// How long to wait for the server to answer a request, tool calls included.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A tool as described by `tools/list`.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ToolInfo {
+    pub name: String,
+
+    #[serde(default)]
+    pub description: String,
+
+    #[serde(default = "empty_schema")]
+    pub input_schema: Value,
+}
+
+fn empty_schema() -> Value {
+    json!({ "type": "object", "properties": {} })
+}
+
+/// A resource as described by `resources/list`.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Resource {
+    pub uri: String,
+    pub name: String,
+
+    #[serde(default)]
+    pub mime_type: Option<String>,
+}
+
+/// Content returned by a tool call or a resource read.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Content {
+    #[serde(rename = "type", default)]
+    type_: String,
+
+    #[serde(default)]
+    text: Option<String>,
+
+    #[serde(default)]
+    resource: Option<Box<Content>>,
+}
+
+impl Content {
+    fn text(&self) -> String {
+        match (&self.text, &self.resource) {
+            (Some(text), _) => text.clone(),
+            (None, Some(resource)) => resource.text(),
+            _ => format!("[{} content]", self.type_),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+/// A JSON-RPC message read from the server.
+#[derive(Deserialize, Debug)]
+struct Message {
+    #[serde(default)]
+    id: Option<Value>,
+
+    #[serde(default)]
+    method: Option<String>,
+
+    #[serde(default)]
+    result: Option<Value>,
+
+    #[serde(default)]
+    error: Option<RpcError>,
+}
+
+struct Io {
+    stdin: Box<dyn AsyncWrite + Send + Unpin>,
+    stdout: Lines<BufReader<Box<dyn AsyncRead + Send + Unpin>>>,
+    next_id: u64,
+}
+
+/// An MCP server run as a child process, speaking JSON-RPC over stdio.
+pub struct Server {
+    pub name: String,
+    io: Mutex<Io>,
+    timeout: Duration,
+
+    // Killed when the server is dropped
+    _child: Option<Child>,
+}
+
+impl Server {
+    /// Start the command with `sh -c` and go through the handshake.
+    pub async fn spawn(command: &str) -> Result<Arc<Self>> {
+        Self::start(command, TIMEOUT).await
+    }
+
+    /// Like `spawn`, giving up on requests after `timeout`.
+    async fn start(command: &str, timeout: Duration) -> Result<Arc<Self>> {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| anyhow!("Cannot start {command}: {e}"))?;
+
+        let stdin = child.stdin.take().ok_or(anyhow!("No stdin"))?;
+        let stdout = child.stdout.take().ok_or(anyhow!("No stdout"))?;
+
+        Self::connect(command, stdout, stdin, Some(child), timeout).await
+    }
+
+    /// Go through the handshake with a server reached through `reader` and
+    /// `writer`, named `name` until it tells its own.
+    async fn connect(
+        name: &str,
+        reader: impl AsyncRead + Send + Unpin + 'static,
+        writer: impl AsyncWrite + Send + Unpin + 'static,
+        child: Option<Child>,
+        timeout: Duration,
+    ) -> Result<Arc<Self>> {
+        let reader: Box<dyn AsyncRead + Send + Unpin> = Box::new(reader);
+        let mut server = Server {
+            name: name.to_string(),
+            io: Mutex::new(Io {
+                stdin: Box::new(writer),
+                stdout: BufReader::new(reader).lines(),
+                next_id: 1,
+            }),
+            timeout,
+            _child: child,
+        };
+
+        let result = server
+            .request(
+                "initialize",
+                json!({
+                    "protocolVersion": PROTOCOL_VERSION,
+                    "capabilities": {},
+                    "clientInfo": { "name": "octo", "version": env!("CARGO_PKG_VERSION") }
+                }),
+            )
+            .await?;
+        if let Some(name) = result["serverInfo"]["name"].as_str() {
+            server.name = name.to_string();
+        }
+        server.notify("notifications/initialized").await?;
+
+        Ok(Arc::new(server))
+    }
+
+    /// Send a message, failing like a read would when the server is gone.
+    async fn write(&self, io: &mut Io, message: Value) -> Result<()> {
+        let mut line = message.to_string();
+        line.push('\n');
+        let written = match io.stdin.write_all(line.as_bytes()).await {
+            Ok(()) => io.stdin.flush().await,
+            Err(error) => Err(error),
+        };
+        match written {
+            Err(error) if error.kind() == io::ErrorKind::BrokenPipe => {
+                Err(anyhow!("{} exited", self.name))
+            }
+            written => Ok(written?),
+        }
+    }
+
+    async fn notify(&self, method: &str) -> Result<()> {
+        let mut io = self.io.lock().await;
+        self.write(&mut io, json!({ "jsonrpc": "2.0", "method": method }))
+            .await
+    }
+
+    /// Send a request and wait for its response, for as long as the timeout.
+    async fn request(&self, method: &str, params: Value) -> Result<Value> {
+        tokio::time::timeout(self.timeout, self.exchange(method, params))
+            .await
+            .map_err(|_| {
+                anyhow!(
+                    "{} did not answer {method} within {}s",
+                    self.name,
+                    self.timeout.as_secs_f64()
+                )
+            })?
+    }
+
+    /// Send a request and wait for its response, answering the server's own
+    /// requests and skipping its notifications meanwhile. Responses to
+    /// requests given up on are skipped too.
+    async fn exchange(&self, method: &str, params: Value) -> Result<Value> {
+        let mut io = self.io.lock().await;
+
+        let id = io.next_id;
+        io.next_id += 1;
+        self.write(
+            &mut io,
+            json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params }),
+        )
+        .await?;
+
+        loop {
+            let line = io
+                .stdout
+                .next_line()
+                .await?
+                .ok_or(anyhow!("{} exited", self.name))?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let message: Message = serde_json::from_str(&line)
+                .map_err(|e| anyhow!("Invalid message from {}: {e}", self.name))?;
+
+            match (message.id, message.method) {
+                // A request from the server, we only know about pings
+                (Some(request), Some(method)) => {
+                    let reply = match method.as_str() {
+                        "ping" => json!({ "jsonrpc": "2.0", "id": request, "result": {} }),
+                        _ => json!({
+                            "jsonrpc": "2.0",
+                            "id": request,
+                            "error": { "code": -32601, "message": "Method not found" }
+                        }),
+                    };
+                    self.write(&mut io, reply).await?;
+                }
+                (Some(response), None) if response == id => {
+                    if let Some(error) = message.error {
+                        return Err(anyhow!("{} ({})", error.message, error.code));
+                    }
+                    return Ok(message.result.unwrap_or(Value::Null));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Collect the pages of a list request.
+    async fn list<T: for<'de> Deserialize<'de>>(&self, method: &str, key: &str) -> Result<Vec<T>> {
+        let mut items = vec![];
+        let mut cursor: Option<String> = None;
+        loop {
+            let params = match &cursor {
+                Some(cursor) => json!({ "cursor": cursor }),
+                None => json!({}),
+            };
+            let mut result = self.request(method, params).await?;
+
+            items.extend(serde_json::from_value::<Vec<T>>(result[key].take())?);
+            match result["nextCursor"].as_str() {
+                Some(next) => cursor = Some(next.to_string()),
+                None => return Ok(items),
+            }
+        }
+    }
+
+    pub async fn tools(&self) -> Result<Vec<ToolInfo>> {
+        self.list("tools/list", "tools").await
+    }
+
+    pub async fn call(&self, name: &str, arguments: Value) -> Result<String> {
+        let result = self
+            .request(
+                "tools/call",
+                json!({ "name": name, "arguments": arguments }),
+            )
+            .await?;
+
+        let content = serde_json::from_value::<Vec<Content>>(result["content"].clone())?;
+        let text = content
+            .iter()
+            .map(Content::text)
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        if result["isError"].as_bool().unwrap_or(false) {
+            return Err(anyhow!(text));
+        }
+        Ok(text)
+    }
+
+    pub async fn resources(&self) -> Result<Vec<Resource>> {
+        self.list("resources/list", "resources").await
+    }
+
+    pub async fn read(&self, uri: &str) -> Result<String> {
+        let result = self
+            .request("resources/read", json!({ "uri": uri }))
+            .await?;
+
+        let contents = serde_json::from_value::<Vec<Content>>(result["contents"].clone())?;
+        if contents.iter().any(|content| content.text.is_none()) {
+            return Err(anyhow!("{uri} is not a text resource"));
+        }
+        Ok(contents
+            .iter()
+            .map(Content::text)
+            .collect::<Vec<_>>()
+            .join("\n"))
+    }
+}
+
+/// Routes a model tool call to the server.
+struct Remote {
+    server: Arc<Server>,
+    name: String,
+}
+
+#[async_trait]
+impl Handler for Remote {
+    async fn call(&self, arguments: Value) -> Result<String> {
+        self.server.call(&self.name, arguments).await
+    }
+}
+
+/// Register the tools offered by the server. Tools named like one already
+/// registered are prefixed with the server name rather than replacing it,
+/// these are returned as the original and the registered name.
+pub async fn register(tools: &mut Tools, server: &Arc<Server>) -> Result<Vec<(String, String)>> {
+    // Tool names are limited to letters, digits, `_` and `-`
+    let prefix = server
+        .name
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() || c == '-' {
+            true => c,
+            false => '_',
+        })
+        .collect::<String>();
+
+    let mut renamed = vec![];
+    for tool in server.tools().await? {
+        let handler = Remote {
+            server: server.clone(),
+            name: tool.name.clone(),
+        };
+
+        let mut name = tool.name.clone();
+        if tools.contains(&name) {
+            name = format!("{prefix}_{}", tool.name);
+            renamed.push((tool.name.clone(), name.clone()));
+        }
+        tools.register(&name, &tool.description, tool.input_schema, handler);
+    }
+    Ok(renamed)
+}
+
+/// Read a resource from the first server which has it.
+pub async fn read(servers: &[Arc<Server>], uri: &str) -> Result<String> {
+    let mut error = anyhow!("No MCP server provides {uri}");
+    for server in servers {
+        match server.read(uri).await {
+            Ok(text) => return Ok(text),
+            Err(e) => error = e,
+        }
+    }
+    Err(error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::DuplexStream;
+
+    /// The fake server built by cargo as the `fake_mcp` example, whose
+    /// replies are also served in-process.
+    mod fake_mcp {
+        include!("../examples/fake_mcp.rs");
+    }
+    use fake_mcp::result;
+
+    /// A minimal MCP server, with an `echo` tool, a failing `fail` tool and
+    /// a single text resource.
+    async fn fake(stream: DuplexStream) {
+        let (reader, mut writer) = tokio::io::split(stream);
+        let mut lines = BufReader::new(reader).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            let request: Value = serde_json::from_str(&line).unwrap();
+
+            // Notifications and responses to our own requests get no response
+            let (Some(id), Some(_)) = (request.get("id"), request.get("method")) else {
+                continue;
+            };
+
+            // Check that the client answers requests coming from the server
+            let mut reply = String::new();
+            if request["method"] == "tools/list" {
+                reply.push_str("{\"jsonrpc\":\"2.0\",\"id\":\"s1\",\"method\":\"ping\"}\n");
+                reply.push_str("{\"jsonrpc\":\"2.0\",\"method\":\"notifications/message\"}\n");
+            }
+
+            let response = match result(&request) {
+                Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+                Err((code, message)) => json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "error": { "code": code, "message": message }
+                }),
+            };
+            reply.push_str(&format!("{response}\n"));
+            writer.write_all(reply.as_bytes()).await.unwrap();
+        }
+    }
+
+    async fn fake_server() -> Arc<Server> {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(fake(server));
+        let (reader, writer) = tokio::io::split(client);
+        Server::connect("fake-mcp", reader, writer, None, TIMEOUT)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_fake_server() {
+        let server = fake_server().await;
+        assert_eq!(server.name, "fake");
+
+        let mut tools = Tools::default();
+        assert!(register(&mut tools, &server).await.unwrap().is_empty());
+
+        let echo = tools.iter().find(|tool| tool.name == "echo").unwrap();
+        assert_eq!(echo.description, "Send back the text");
+        assert_eq!(echo.parameters["required"], json!(["text"]));
+
+        assert_eq!(tools.call("echo", r#"{"text": "hi"}"#).await, "hi");
+        assert_eq!(tools.call("fail", "").await, "Error: Something broke");
+
+        let resources = server.resources().await.unwrap();
+        assert_eq!(resources[0].uri, "fake://notes");
+
+        let servers = [server];
+        assert_eq!(
+            read(&servers, "fake://notes").await.unwrap(),
+            "Remember the milk"
+        );
+        assert!(read(&servers, "fake://missing").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_tool_collisions() {
+        let mut tools = Tools::default();
+        tools.register(
+            "echo",
+            "Built-in",
+            json!({}),
+            |_| Ok("built-in".to_string()),
+        );
+
+        let server = fake_server().await;
+        assert_eq!(
+            register(&mut tools, &server).await.unwrap(),
+            [("echo".to_string(), "fake_echo".to_string())]
+        );
+        assert_eq!(tools.call("echo", "{}").await, "built-in");
+        assert_eq!(tools.call("fake_echo", r#"{"text": "hi"}"#).await, "hi");
+    }
+
+    /// The command running the `fake_mcp` example, built with the tests.
+    fn fake_command(mode: &str) -> String {
+        let exe = std::env::current_exe().unwrap();
+        let dir = exe.parent().and_then(|deps| deps.parent()).unwrap();
+        let path = dir.join("examples").join("fake_mcp");
+        assert!(
+            path.exists(),
+            "build the examples first: cargo test --all-targets"
+        );
+        format!("{} {mode}", path.display())
+    }
+
+    #[tokio::test]
+    async fn test_spawned_server() {
+        // Over the child's stdio, with its logs on stderr
+        let server = Server::spawn(&fake_command("")).await.unwrap();
+        assert_eq!(server.name, "fake");
+        let mut tools = Tools::default();
+        register(&mut tools, &server).await.unwrap();
+        assert_eq!(tools.call("echo", r#"{"text": "hi"}"#).await, "hi");
+        assert_eq!(
+            read(&[server], "fake://notes").await.unwrap(),
+            "Remember the milk"
+        );
+
+        // Gone after the handshake
+        let server = Server::spawn(&fake_command("exit")).await.unwrap();
+        let error = server.tools().await.unwrap_err();
+        assert_eq!(error.to_string(), "fake exited");
+
+        // Never answering
+        let server = Server::start(&fake_command("hang"), Duration::from_millis(200))
+            .await
+            .unwrap();
+        let error = server.tools().await.unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "fake did not answer tools/list within 0.2s"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_spawn_failures() {
+        let error = Server::spawn("exit 0").await.err().unwrap();
+        assert_eq!(error.to_string(), "exit 0 exited");
+    }
+}
@@ -0,0 +1,490 @@
+use crate::chat::{self, Data, Settings};
+use crate::conversation::{Callback, Conversation, Metadata, Role, State, Usage};
+use crate::error::{Details, OctoError};
+use crate::provider::Auth;
+
+use std::ops::AddAssign;
+
+use anyhow::Result;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+
+use async_trait::async_trait;
+use tokio_stream::StreamExt;
+
+#[derive(Serialize, Debug)]
+struct Options {
+    temperature: f64,
+    top_p: f64,
+
+    /// Maximum number of tokens to predict.
+    num_predict: i64,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<i64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop: Option<Vec<String>>,
+
+    presence_penalty: f64,
+    frequency_penalty: f64,
+}
+
+#[derive(Serialize, Debug)]
+struct Function<'a> {
+    name: &'a str,
+
+    /// An object, where the OpenAI dialect has a JSON string.
+    arguments: serde_json::Value,
+}
+
+#[derive(Serialize, Debug)]
+struct ToolCall<'a> {
+    function: Function<'a>,
+}
+
+/// A message of the history, as Ollama expects it.
+#[derive(Serialize, Debug)]
+struct Message<'a> {
+    role: &'a str,
+    content: &'a str,
+
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tool_calls: Vec<ToolCall<'a>>,
+}
+
+impl<'a> From<&'a Data> for Message<'a> {
+    fn from(data: &'a Data) -> Self {
+        let calls = data.tool_calls.as_deref().unwrap_or_default();
+        Message {
+            role: data.role.as_deref().unwrap_or("user"),
+            content: data.content.as_deref().unwrap_or_default(),
+            tool_calls: calls
+                .iter()
+                .map(|call| ToolCall {
+                    function: Function {
+                        name: &call.function.name,
+                        arguments: call
+                            .function
+                            .arguments
+                            .as_deref()
+                            .and_then(|arguments| serde_json::from_str(arguments).ok())
+                            .unwrap_or_else(|| serde_json::json!({})),
+                    },
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct Request<'a> {
+    model: &'a str,
+    messages: Vec<Message<'a>>,
+    stream: bool,
+
+    /// `json`, or the schema the reply must match.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<serde_json::Value>,
+
+    options: Options,
+}
+
+#[derive(Deserialize, Debug)]
+struct Reply {
+    #[serde(default)]
+    content: String,
+}
+
+/// A line of the NDJSON stream, or the whole reply.
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum Response {
+    Error {
+        error: String,
+    },
+    Chunk {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        model: Option<String>,
+
+        #[serde(skip_serializing_if = "Option::is_none")]
+        message: Option<Reply>,
+
+        done: bool,
+
+        #[serde(skip_serializing_if = "Option::is_none")]
+        done_reason: Option<String>,
+
+        #[serde(skip_serializing_if = "Option::is_none")]
+        prompt_eval_count: Option<i64>,
+
+        #[serde(skip_serializing_if = "Option::is_none")]
+        eval_count: Option<i64>,
+    },
+}
+
+#[derive(Deserialize, Debug)]
+struct Model {
+    name: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct Tags {
+    models: Vec<Model>,
+}
+
+/// Conversation through Ollama's `/api/chat` endpoint.
+pub struct Ollama {
+    client: Client,
+    api_key: String,
+    url: reqwest::Url,
+    model: String,
+    settings: Settings,
+    history: Vec<Data>,
+    auth: Auth,
+    metadata: Metadata,
+}
+
+impl Ollama {
+    pub fn new(api_key: &str, url: &str, model: &str, settings: &Settings) -> Result<Self> {
+        Ok(Ollama {
+            client: Client::new(),
+            api_key: api_key.to_string(),
+            url: url.parse()?,
+            model: model.to_string(),
+            settings: settings.clone(),
+            history: vec![],
+            auth: Auth::None,
+            metadata: Metadata::default(),
+        })
+    }
+
+    pub fn auth(mut self, auth: Auth) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    fn authorize(&self, builder: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        self.auth.apply(builder, &self.api_key)
+    }
+
+    fn request(&self) -> Request<'_> {
+        Request {
+            model: &self.model,
+            messages: self.history.iter().map(Message::from).collect(),
+            stream: true,
+            format: match &self.settings.schema {
+                Some(schema) => Some(schema.clone()),
+                None => self.settings.json.then(|| "json".into()),
+            },
+            options: Options {
+                temperature: self.settings.temperature,
+                top_p: self.settings.top_p,
+                num_predict: self.settings.max_tokens,
+                seed: self.settings.seed,
+                stop: self.settings.stop.clone(),
+                presence_penalty: self.settings.presence_penalty,
+                frequency_penalty: self.settings.frequency_penalty,
+            },
+        }
+    }
+}
+
+/// Map Ollama's done reason onto the conversation state.
+fn finish(reason: &str) -> State<'static> {
+    match reason {
+        "length" => State::OutOfCharacters,
+        _ => State::Stop,
+    }
+}
+
+#[async_trait]
+impl Conversation for Ollama {
+    fn build(&mut self, role: Role, message: &str) -> &mut dyn Conversation {
+        self.history.push(Data::new(role, message));
+        self
+    }
+
+    fn set_system(&mut self, prompt: &str) -> &mut dyn Conversation {
+        chat::set_system(&mut self.history, prompt);
+        self
+    }
+
+    fn system(&self) -> Option<&str> {
+        chat::system(&self.history)
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn set_model(&mut self, model: &str) {
+        self.model = model.to_string();
+    }
+
+    fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    fn settings_mut(&mut self) -> &mut Settings {
+        &mut self.settings
+    }
+
+    fn history(&self) -> &[Data] {
+        &self.history
+    }
+
+    fn restore(&mut self, history: Vec<Data>) {
+        self.history = history;
+    }
+
+    fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    async fn models(&self) -> Result<Vec<String>> {
+        let url = self.url.join("/api/tags")?;
+        let response = self.authorize(self.client.get(url)).send().await?;
+        let tags = response.error_for_status()?.json::<Tags>().await?;
+        Ok(tags.models.into_iter().map(|model| model.name).collect())
+    }
+
+    async fn send(&mut self, f: Callback<'_>) -> Result<()> {
+        let response = self
+            .authorize(self.client.post(self.url.clone()))
+            .json(&self.request())
+            .send()
+            .await
+            .map_err(OctoError::network)?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let headers = response.headers().clone();
+            let body = response.text().await.unwrap_or_default();
+            let said = match serde_json::from_str::<Response>(&body) {
+                Ok(Response::Error { error }) => Some(error),
+                _ => None,
+            };
+            let mut details = Details::response(status, &headers, body);
+            if let Some(message) = said {
+                details.message = message;
+            }
+            return Err(OctoError::from(details).into());
+        }
+
+        f(State::Start);
+
+        // The reply is newline delimited JSON, where lines may span chunks
+        let mut text = String::new();
+        let mut buffer = vec![];
+
+        let mut stream = response.bytes_stream();
+        let mut ended = false;
+        while !ended {
+            match stream.next().await {
+                Some(bytes) => buffer.extend_from_slice(&bytes.map_err(OctoError::network)?),
+                // The last line may come without a newline
+                None => {
+                    buffer.push(b'\n');
+                    ended = true;
+                }
+            }
+
+            while let Some(end) = buffer.iter().position(|b| *b == b'\n') {
+                let line = buffer.drain(..=end).collect::<Vec<_>>();
+                if line.iter().all(u8::is_ascii_whitespace) {
+                    continue;
+                }
+
+                match serde_json::from_slice::<Response>(&line).map_err(OctoError::protocol)? {
+                    Response::Error { error } => {
+                        let details = Details {
+                            message: error,
+                            ..Default::default()
+                        };
+                        return Err(OctoError::from(details).into());
+                    }
+                    Response::Chunk {
+                        model,
+                        message,
+                        done,
+                        done_reason,
+                        prompt_eval_count,
+                        eval_count,
+                    } => {
+                        if let Some(Reply { content }) = message.filter(|m| !m.content.is_empty()) {
+                            text.add_assign(&content);
+
+                            // Only send message chunks if the user requested stream
+                            if self.settings.stream {
+                                f(State::Message(&content));
+                            }
+                        }
+
+                        if done {
+                            if !self.settings.stream {
+                                f(State::Message(&text));
+                            }
+                            let reason = done_reason.unwrap_or("stop".to_string());
+                            f(finish(&reason));
+
+                            let (prompt, completion) =
+                                (prompt_eval_count.unwrap_or(0), eval_count.unwrap_or(0));
+                            self.metadata = Metadata {
+                                model,
+                                finish_reason: Some(reason),
+                                usage: Some(Usage {
+                                    prompt_tokens: prompt,
+                                    completion_tokens: completion,
+                                    total_tokens: prompt + completion,
+                                }),
+                                ..Default::default()
+                            };
+
+                            self.history.push(Data::new(Role::Assistant, &text));
+                            if let Some(usage) = self.metadata.usage {
+                                f(State::Usage(usage));
+                            }
+                            f(State::Done);
+                            return Ok(());
+                        }
+                    }
+                }
+            }
+        }
+
+        Err(OctoError::ended().into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use serde_json::json;
+    use wiremock::{
+        matchers::{body_partial_json, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    #[tokio::test]
+    async fn test_chat_ndjson() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/chat"))
+            .and(body_partial_json(json!({
+                "model": "llama3",
+                "messages": [{ "role": "user", "content": "Hello" }],
+                "options": { "num_predict": 1024 }
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(
+                include_str!("../tests/fixtures/ollama/chat.ndjson"),
+                "application/x-ndjson",
+            ))
+            .mount(&server)
+            .await;
+
+        let settings = Settings {
+            stream: true,
+            ..Default::default()
+        };
+        let url = format!("{}/api/chat", server.uri());
+        let mut ollama = Ollama::new("", &url, "llama3", &settings).unwrap();
+
+        let states = Mutex::new(vec![]);
+        ollama
+            .build(Role::User, "Hello")
+            .send(&|state| states.lock().unwrap().push(state.to_string()))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            *states.lock().unwrap(),
+            [
+                "start",
+                "message: Hello",
+                "message: !",
+                "stop",
+                "usage: 10 + 2 tokens",
+                "done"
+            ]
+        );
+        assert_eq!(ollama.history()[1].content.as_deref(), Some("Hello!"));
+    }
+
+    #[tokio::test]
+    async fn test_last_line() {
+        // Without a newline after the last line
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(
+                include_str!("../tests/fixtures/ollama/chat.ndjson").trim_end(),
+                "application/x-ndjson",
+            ))
+            .mount(&server)
+            .await;
+
+        let url = format!("{}/api/chat", server.uri());
+        let mut ollama = Ollama::new("", &url, "llama3", &Settings::default()).unwrap();
+        ollama
+            .build(Role::User, "Hello")
+            .send(&|_| {})
+            .await
+            .unwrap();
+        assert_eq!(ollama.history()[1].content.as_deref(), Some("Hello!"));
+    }
+
+    #[test]
+    fn test_request_messages() {
+        let mut ollama =
+            Ollama::new("", "http://localhost", "llama3", &Settings::default()).unwrap();
+        let call: Data = serde_json::from_value(json!({
+            "role": "assistant",
+            "tool_calls": [{
+                "id": "call_1",
+                "type": "function",
+                "function": { "name": "add", "arguments": "{\"a\": 1}" }
+            }]
+        }))
+        .unwrap();
+        ollama.build(Role::User, "Add");
+        ollama.restore([ollama.history(), &[call]].concat());
+        ollama.build(Role::Tool, "1");
+
+        let request = serde_json::to_value(ollama.request()).unwrap();
+        assert_eq!(
+            request["messages"],
+            json!([
+                { "role": "user", "content": "Add" },
+                {
+                    "role": "assistant",
+                    "content": "",
+                    "tool_calls": [{ "function": { "name": "add", "arguments": { "a": 1 } } }]
+                },
+                { "role": "tool", "content": "1" }
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_list_models() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/tags"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "models": [{ "name": "llama3:latest" }, { "name": "mistral:7b" }]
+            })))
+            .mount(&server)
+            .await;
+
+        let url = format!("{}/api/chat", server.uri());
+        let ollama = Ollama::new("", &url, "", &Settings::default()).unwrap();
+
+        assert_eq!(
+            ollama.models().await.unwrap(),
+            ["llama3:latest", "mistral:7b"]
+        );
+    }
+}
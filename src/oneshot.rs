@@ -0,0 +1,240 @@
+use std::{io::Write, process::ExitCode, sync::Mutex};
+
+use anyhow::Result;
+
+use crate::conversation::{Conversation, Role, State};
+use crate::output::{self, Output};
+use crate::retry;
+use crate::schema;
+use crate::window::Window;
+
+/// How a single turn ended, reported as the exit status.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    Done,
+
+    /// The reply was cut at the maximum number of tokens.
+    Truncated,
+
+    /// The reply was stopped by the provider content filter.
+    Filtered,
+
+    /// The JSON mode reply is not valid, even after asking again.
+    Invalid,
+}
+
+impl From<Outcome> for ExitCode {
+    fn from(outcome: Outcome) -> Self {
+        // 1 and 5 and up are left to errors, see OctoError::exit_code
+        ExitCode::from(match outcome {
+            Outcome::Done => 0,
+            Outcome::Truncated => 2,
+            Outcome::Filtered => 3,
+            Outcome::Invalid => 4,
+        })
+    }
+}
+
+/// Where the reply is printed, stdout but in tests.
+type Out<'a> = Mutex<&'a mut (dyn Write + Send)>;
+
+/// Send the conversation, printing the reply to `out` as it comes in.
+/// Notices go to stderr, leaving `out` to the reply only.
+async fn send(chat: &mut dyn Conversation, output: Output, out: &Out<'_>) -> Result<Outcome> {
+    let json = chat.settings().json;
+    let outcome = Mutex::new(Outcome::Done);
+
+    // Whether the reply ends with a newline, to end it with one
+    let newline = Mutex::new(true);
+
+    // Whether a retry was announced, once per wait rather than every second
+    let waiting = Mutex::new(false);
+
+    retry::send(chat, &|state| {
+        match state {
+            State::OutOfCharacters => *outcome.lock().unwrap() = Outcome::Truncated,
+            State::ContentFilter => *outcome.lock().unwrap() = Outcome::Filtered,
+            _ => {}
+        }
+
+        let retrying = matches!(state, State::Retry(..));
+        let announced = std::mem::replace(&mut *waiting.lock().unwrap(), retrying);
+
+        let mut out = out.lock().unwrap();
+        match (output, state) {
+            (Output::Ndjson, state) => writeln!(out, "{}", output::event(&state)).unwrap(),
+            (Output::Json, _) | (Output::Text, State::Message(_)) if json => {}
+            (Output::Text, State::Message(text)) => {
+                write!(out, "{text}").unwrap();
+                out.flush().unwrap();
+                *newline.lock().unwrap() = text.ends_with('\n');
+            }
+            (Output::Text, State::Tool(name, arguments)) => eprintln!("> {name} {arguments}"),
+            (Output::Text, State::Retry(reason, seconds)) if !announced => {
+                eprintln!("{reason}, retrying in {seconds}s")
+            }
+            (Output::Text, State::Done) if !*newline.lock().unwrap() => writeln!(out).unwrap(),
+            _ => {}
+        }
+    })
+    .await?;
+
+    Ok(outcome.into_inner().unwrap())
+}
+
+/// Send a single user message and print the reply to `out` in the given
+/// format.
+pub async fn run(
+    chat: &mut dyn Conversation,
+    message: &str,
+    output: Output,
+    window: &Window,
+    out: &mut (dyn Write + Send),
+) -> Result<Outcome> {
+    chat.build(Role::User, message);
+    if let Some(fitted) = window.fit(chat).await? {
+        eprintln!("{fitted}");
+    }
+    let out = Mutex::new(out);
+    let mut outcome = send(chat, output, &out).await?;
+
+    // JSON mode replies are checked, and asked again once if invalid
+    let mut checked = None;
+    if chat.settings().json {
+        let mut result = schema::check(chat);
+        if let (Err(errors), Outcome::Done) = (&result, outcome) {
+            chat.build(Role::User, &schema::retry(errors));
+            outcome = send(chat, output, &out).await?;
+            result = schema::check(chat);
+        }
+        if result.is_err() && outcome == Outcome::Done {
+            outcome = Outcome::Invalid;
+        }
+        checked = Some(result);
+    }
+
+    let out = out.into_inner().unwrap();
+    match (output, checked) {
+        (Output::Text, Some(Ok(value))) => {
+            writeln!(out, "{}", serde_json::to_string_pretty(&value)?)?
+        }
+        (Output::Text, Some(Err(errors))) => {
+            // Print what came anyway
+            let reply = chat
+                .history()
+                .last()
+                .and_then(|data| data.content.as_deref());
+            writeln!(out, "{}", reply.unwrap_or_default())?;
+            eprintln!("Invalid JSON reply:\n{}", errors.join("\n"));
+        }
+        (Output::Json, checked) => {
+            let mut reply = output::reply(chat);
+            match checked {
+                Some(Ok(value)) => reply["json"] = value,
+                Some(Err(errors)) => reply["errors"] = errors.into(),
+                None => {}
+            }
+            writeln!(out, "{reply}")?;
+        }
+        (Output::Ndjson, Some(Err(errors))) => {
+            eprintln!("Invalid JSON reply:\n{}", errors.join("\n"))
+        }
+        _ => {}
+    }
+
+    if output == Output::Text {
+        report(outcome);
+    }
+    Ok(outcome)
+}
+
+fn report(outcome: Outcome) {
+    match outcome {
+        Outcome::Truncated => eprintln!("Reply truncated at the maximum number of tokens"),
+        Outcome::Filtered => eprintln!("Reply stopped by the provider content filter"),
+        Outcome::Done | Outcome::Invalid => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+    use wiremock::{matchers::method, Mock, MockServer, ResponseTemplate};
+
+    use crate::chat::{Chat, Settings};
+
+    async fn mount(server: &MockServer, fixture: &str, times: u64) {
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(fixture, "text/event-stream"))
+            .up_to_n_times(times)
+            .mount(server)
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_outcomes() {
+        let server = MockServer::start().await;
+        mount(
+            &server,
+            include_str!("../tests/fixtures/openai/length.sse"),
+            1,
+        )
+        .await;
+
+        let mut chat = Chat::new("key", &server.uri(), "model", &Settings::default()).unwrap();
+        let mut out = vec![];
+        let outcome = run(
+            &mut chat,
+            "Tell me a story",
+            Output::Text,
+            &Window::default(),
+            &mut out,
+        )
+        .await
+        .unwrap();
+        assert_eq!(outcome, Outcome::Truncated);
+        assert_eq!(String::from_utf8(out).unwrap(), "Once upon a time\n");
+        assert_eq!(ExitCode::from(outcome), ExitCode::from(2));
+
+        // An invalid JSON reply is sent back once, the second one is valid
+        let server = MockServer::start().await;
+        mount(
+            &server,
+            include_str!("../tests/fixtures/openai/answer.sse"),
+            1,
+        )
+        .await;
+        mount(
+            &server,
+            include_str!("../tests/fixtures/openai/json.sse"),
+            1,
+        )
+        .await;
+
+        let settings = Settings {
+            json: true,
+            schema: Some(json!({ "type": "object", "required": ["port"] })),
+            ..Default::default()
+        };
+        let mut chat = Chat::new("key", &server.uri(), "model", &settings).unwrap();
+        let mut out = vec![];
+        assert_eq!(
+            run(
+                &mut chat,
+                "Port?",
+                Output::Json,
+                &Window::default(),
+                &mut out
+            )
+            .await
+            .unwrap(),
+            Outcome::Done
+        );
+        let reply: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(reply["json"], json!({ "port": 8080 }));
+        assert_eq!(chat.history().len(), 4);
+        assert_eq!(schema::check(&chat).unwrap(), json!({ "port": 8080 }));
+    }
+}
@@ -0,0 +1,146 @@
+use clap::ValueEnum;
+use serde_json::{json, Value};
+
+use crate::{
+    conversation::{Conversation, State},
+    error::OctoError,
+};
+
+/// How one-shot replies are printed.
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq)]
+pub enum Output {
+    /// The reply as plain text
+    #[default]
+    Text,
+
+    /// A single object with the reply and its metadata, once complete
+    Json,
+
+    /// One JSON object per conversation event, as they come in
+    Ndjson,
+}
+
+/// A conversation state as a JSON object, named like its `Display`.
+pub fn event(state: &State) -> Value {
+    match state {
+        State::Start => json!({ "event": "start" }),
+        State::Stop => json!({ "event": "stop" }),
+        State::Message(text) => json!({ "event": "message", "text": text }),
+        State::OutOfCharacters => json!({ "event": "length" }),
+        State::ContentFilter => json!({ "event": "content_filter" }),
+        State::ToolCalls => json!({ "event": "tool_calls" }),
+        State::Tool(name, arguments) => {
+            json!({ "event": "tool", "name": name, "arguments": arguments })
+        }
+        State::Usage(usage) => json!({
+            "event": "usage",
+            "prompt_tokens": usage.prompt_tokens,
+            "completion_tokens": usage.completion_tokens,
+            "total_tokens": usage.total_tokens,
+        }),
+        State::Retry(reason, seconds) => {
+            json!({ "event": "retry", "reason": reason, "seconds": seconds })
+        }
+        State::Done => json!({ "event": "done" }),
+    }
+}
+
+/// The last reply and what the provider told about it.
+pub fn reply(chat: &dyn Conversation) -> Value {
+    let metadata = chat.metadata();
+    let message = chat
+        .history()
+        .last()
+        .and_then(|data| data.content.as_deref());
+
+    json!({
+        "message": message.unwrap_or_default(),
+        "model": metadata.model.as_deref().unwrap_or(chat.model()),
+        "system_fingerprint": metadata.system_fingerprint,
+        "finish_reason": metadata.finish_reason,
+        "usage": metadata.usage,
+    })
+}
+
+/// A failure as a JSON object, with what the provider told about it.
+pub fn error(error: &anyhow::Error) -> Value {
+    let Some(octo) = error.downcast_ref::<OctoError>() else {
+        return json!({ "error": { "message": format!("{error:#}") } });
+    };
+    let details = octo.details();
+
+    json!({
+        "error": {
+            "message": format!("{error:#}"),
+            "kind": octo.kind(),
+            "status": details.and_then(|details| details.status).map(|status| status.as_u16()),
+            "type": details.and_then(|details| details.type_.as_deref()),
+            "code": details.and_then(|details| details.code.as_deref()),
+            "param": details.and_then(|details| details.param.as_deref()),
+            "hint": octo.hint(),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::StatusCode;
+
+    use super::*;
+    use crate::error::Details;
+
+    #[test]
+    fn test_events() {
+        let text = "Hi".to_string();
+        let events = [
+            State::Start,
+            State::Message(&text),
+            State::Tool("grep", "{}"),
+            State::OutOfCharacters,
+            State::Done,
+        ]
+        .iter()
+        .map(|state| event(state).to_string())
+        .collect::<Vec<_>>();
+
+        assert_eq!(
+            events,
+            [
+                r#"{"event":"start"}"#,
+                r#"{"event":"message","text":"Hi"}"#,
+                r#"{"arguments":"{}","event":"tool","name":"grep"}"#,
+                r#"{"event":"length"}"#,
+                r#"{"event":"done"}"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_error() {
+        let details = Details {
+            status: Some(StatusCode::TOO_MANY_REQUESTS),
+            message: "Slow down".to_string(),
+            type_: Some("rate_limit_error".to_string()),
+            ..Default::default()
+        };
+        let limited = anyhow::Error::from(OctoError::from(details));
+        assert_eq!(
+            error(&limited)["error"],
+            json!({
+                "message": "Slow down (429 Too Many Requests, rate_limit_error)",
+                "kind": "rate_limit",
+                "status": 429,
+                "type": "rate_limit_error",
+                "code": null,
+                "param": null,
+                "hint": "Rate limited by the provider, wait before sending again",
+            })
+        );
+
+        let other = anyhow::anyhow!("No such profile");
+        assert_eq!(
+            error(&other),
+            json!({ "error": { "message": "No such profile" } })
+        );
+    }
+}
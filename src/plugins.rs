@@ -0,0 +1,253 @@
+use std::{fs, path::Path, process::Stdio, time::Duration};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    process::Command,
+};
+
+use crate::tools::{Group, Handler, Tools};
+
+fn default_timeout() -> f64 {
+    30.0
+}
+
+fn default_max_output() -> usize {
+    64 * 1024
+}
+
+fn default_parameters() -> Value {
+    serde_json::json!({ "type": "object", "properties": {} })
+}
+
+/// A tool implemented by an external command.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Plugin {
+    pub name: String,
+    pub description: String,
+
+    /// JSON schema of the arguments object.
+    #[serde(default = "default_parameters")]
+    pub parameters: Value,
+
+    /// Command line run by `sh -c`, receiving the arguments as JSON on stdin.
+    pub command: String,
+
+    /// Seconds the command can run before it is killed.
+    #[serde(default = "default_timeout")]
+    pub timeout: f64,
+
+    /// Bytes of stdout sent back to the model.
+    #[serde(default = "default_max_output")]
+    pub max_output: usize,
+}
+
+#[derive(Deserialize, Debug)]
+struct Config {
+    #[serde(default)]
+    tools: Vec<Plugin>,
+}
+
+/// Read the plugins declared in a TOML or JSON file, picked by extension.
+pub fn load(path: &Path) -> Result<Vec<Plugin>> {
+    let content =
+        fs::read_to_string(path).map_err(|e| anyhow!("Cannot read {}: {e}", path.display()))?;
+
+    let config: Config = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("json") => serde_json::from_str(&content)?,
+        _ => toml::from_str(&content)?,
+    };
+
+    for plugin in &config.tools {
+        if Duration::try_from_secs_f64(plugin.timeout).is_err() {
+            return Err(anyhow!(
+                "Invalid timeout {} for {} in {}, expected a number of seconds",
+                plugin.timeout,
+                plugin.name,
+                path.display()
+            ));
+        }
+    }
+    Ok(config.tools)
+}
+
+/// Register every plugin declared in the file at `path`.
+pub fn register(tools: &mut Tools, path: &Path) -> Result<()> {
+    for plugin in load(path)? {
+        let (name, description) = (plugin.name.clone(), plugin.description.clone());
+        let parameters = plugin.parameters.clone();
+        tools.register(&name, &description, parameters, plugin);
+    }
+    Ok(())
+}
+
+impl Plugin {
+    async fn run(&self, arguments: &Value) -> Result<String> {
+        let (mut child, mut group) = Group::spawn(
+            Command::new("sh")
+                .arg("-c")
+                .arg(&self.command)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .kill_on_drop(true),
+        )?;
+
+        // Closing stdin once written lets the command read it to the end.
+        // Commands ignoring their input may exit before it is written.
+        let mut stdin = child.stdin.take().ok_or(anyhow!("No stdin"))?;
+        match stdin.write_all(arguments.to_string().as_bytes()).await {
+            Err(e) if e.kind() != std::io::ErrorKind::BrokenPipe => return Err(e.into()),
+            _ => drop(stdin),
+        }
+
+        // Stderr is drained meanwhile, a command filling its pipe would
+        // otherwise block forever
+        let mut reader = child.stderr.take().ok_or(anyhow!("No stderr"))?;
+        let max_output = self.max_output as u64;
+        let stderr = tokio::spawn(async move {
+            let mut stderr = vec![];
+            (&mut reader)
+                .take(max_output)
+                .read_to_end(&mut stderr)
+                .await?;
+            tokio::io::copy(&mut reader, &mut tokio::io::sink()).await?;
+            Ok::<_, std::io::Error>(stderr)
+        });
+
+        // Read one byte past the cap to know whether the output was cut
+        let mut stdout = vec![];
+        let reader = child.stdout.take().ok_or(anyhow!("No stdout"))?;
+        reader.take(max_output + 1).read_to_end(&mut stdout).await?;
+
+        if stdout.len() > self.max_output {
+            stdout.truncate(self.max_output);
+            let mut text = String::from_utf8_lossy(&stdout).to_string();
+            text.push_str("\n[truncated]");
+            return Ok(text);
+        }
+
+        let status = child.wait().await?;
+        group.release();
+        let stderr = stderr.await??;
+
+        let text = String::from_utf8_lossy(&stdout).to_string();
+        if !status.success() {
+            let stderr = String::from_utf8_lossy(&stderr);
+            return Err(anyhow!(
+                "{} failed with {}: {}",
+                self.name,
+                status,
+                stderr.trim()
+            ));
+        }
+        Ok(text)
+    }
+}
+
+#[async_trait]
+impl Handler for Plugin {
+    async fn call(&self, arguments: Value) -> Result<String> {
+        let timeout = Duration::from_secs_f64(self.timeout);
+        tokio::time::timeout(timeout, self.run(&arguments))
+            .await
+            .map_err(|_| anyhow!("{} timed out after {}s", self.name, self.timeout))?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_run_plugins() {
+        let config = r#"
+            [[tools]]
+            name = "echo"
+            description = "Send back the arguments"
+            command = "cat"
+            parameters = { type = "object", properties = { text = { type = "string" } } }
+
+            [[tools]]
+            name = "fail"
+            description = "Always fails"
+            command = "echo oops >&2; exit 3"
+
+            [[tools]]
+            name = "slow"
+            description = "Never ends in time"
+            command = "sleep 5"
+            timeout = 0.2
+
+            [[tools]]
+            name = "long"
+            description = "Prints too much"
+            command = "yes"
+            max_output = 4
+
+            [[tools]]
+            name = "noisy"
+            description = "Fills the stderr pipe"
+            command = "head -c 200000 /dev/zero >&2; echo done"
+        "#;
+
+        let path = std::env::temp_dir().join(format!("octo-plugins-{}.toml", std::process::id()));
+        fs::write(&path, config).unwrap();
+
+        let mut tools = Tools::default();
+        register(&mut tools, &path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let echo = tools.iter().find(|tool| tool.name == "echo").unwrap();
+        assert_eq!(echo.parameters["properties"]["text"]["type"], "string");
+
+        assert_eq!(
+            tools.call("echo", r#"{"text": "hi"}"#).await,
+            r#"{"text":"hi"}"#
+        );
+        assert_eq!(
+            tools.call("fail", "").await,
+            "Error: fail failed with exit status: 3: oops"
+        );
+        assert_eq!(
+            tools.call("slow", "").await,
+            "Error: slow timed out after 0.2s"
+        );
+        assert_eq!(tools.call("long", "").await, "y\ny\n\n[truncated]");
+        assert_eq!(tools.call("noisy", "").await, "done\n");
+    }
+
+    #[tokio::test]
+    async fn test_timeouts() {
+        let dir = std::env::temp_dir().join(format!("octo-timeouts-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        for timeout in ["-1.0", "nan", "inf"] {
+            let path = dir.join("invalid.toml");
+            let config = format!(
+                "[[tools]]\nname = \"t\"\ndescription = \"\"\ncommand = \"true\"\ntimeout = {timeout}"
+            );
+            fs::write(&path, config).unwrap();
+            assert!(load(&path).is_err(), "{timeout}");
+        }
+
+        // What the command started in the background is killed with it
+        let marker = dir.join("marker");
+        let plugin = Plugin {
+            name: "spawner".to_string(),
+            description: String::new(),
+            parameters: default_parameters(),
+            command: format!("(sleep 1; touch {}) & sleep 5", marker.display()),
+            timeout: 0.2,
+            max_output: default_max_output(),
+        };
+        assert!(plugin.call(Value::Null).await.is_err());
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert!(!marker.exists(), "the background command kept running");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
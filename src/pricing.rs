@@ -0,0 +1,215 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+
+use crate::config;
+use crate::conversation::Usage;
+
+/// Dollars per million tokens.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Price {
+    pub input: f64,
+    pub output: f64,
+}
+
+impl Price {
+    pub fn cost(&self, usage: &Usage) -> f64 {
+        (usage.prompt_tokens as f64 * self.input + usage.completion_tokens as f64 * self.output)
+            / 1_000_000.0
+    }
+}
+
+const fn price(input: f64, output: f64) -> Price {
+    Price { input, output }
+}
+
+/// Public list prices, matched by model name prefix. They change often, hence
+/// the overrides, and costs are only ever an estimate.
+const PRICES: &[(&str, Price)] = &[
+    ("gpt-3.5-turbo", price(0.5, 1.5)),
+    ("gpt-3.5-turbo-1106", price(1.0, 2.0)),
+    ("gpt-4", price(30.0, 60.0)),
+    ("gpt-4-32k", price(60.0, 120.0)),
+    ("gpt-4-turbo", price(10.0, 30.0)),
+    ("gpt-4-1106-preview", price(10.0, 30.0)),
+    ("gpt-4-0125-preview", price(10.0, 30.0)),
+    ("gpt-4o", price(5.0, 15.0)),
+    ("gpt-4o-mini", price(0.15, 0.6)),
+    ("claude-3-haiku", price(0.25, 1.25)),
+    ("claude-3-sonnet", price(3.0, 15.0)),
+    ("claude-3-5-sonnet", price(3.0, 15.0)),
+    ("claude-3-opus", price(15.0, 75.0)),
+    ("gemini-pro", price(0.5, 1.5)),
+    ("gemini-1.0-pro", price(0.5, 1.5)),
+    ("gemini-1.5-flash", price(0.35, 1.05)),
+    ("gemini-1.5-pro", price(3.5, 10.5)),
+    ("mistral-tiny", price(0.25, 0.25)),
+    ("mistral-small", price(2.0, 6.0)),
+    ("mistral-medium", price(2.7, 8.1)),
+    ("mistral-large", price(8.0, 24.0)),
+    ("mistralai/Mixtral-8x7B-Instruct-v0.1", price(0.6, 0.6)),
+];
+
+/// Where the price overrides are read from by default.
+pub fn default_path() -> Option<PathBuf> {
+    config::dir().map(|dir| dir.join("prices.toml"))
+}
+
+/// The built-in price table, with the user overrides on top.
+#[derive(Debug, Default)]
+pub struct Prices {
+    overrides: HashMap<String, Price>,
+}
+
+impl Prices {
+    /// Read overrides from a TOML file of `"<model>" = { input = 0.5, output = 1.5 }`
+    /// entries. A missing file is only an error if it was asked for explicitly.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let (path, required) = match path {
+            Some(path) => (path.to_path_buf(), true),
+            None => match default_path() {
+                Some(path) => (path, false),
+                None => return Ok(Prices::default()),
+            },
+        };
+        if !required && !path.exists() {
+            return Ok(Prices::default());
+        }
+
+        let content = fs::read_to_string(&path)
+            .map_err(|e| anyhow!("Cannot read {}: {e}", path.display()))?;
+        let overrides =
+            toml::from_str(&content).map_err(|e| anyhow!("Invalid {}: {e}", path.display()))?;
+        Ok(Prices { overrides })
+    }
+
+    /// The price of a model, from the longest matching prefix.
+    pub fn find(&self, model: &str) -> Option<Price> {
+        if let Some(price) = self.overrides.get(model) {
+            return Some(*price);
+        }
+
+        let overrides = self
+            .overrides
+            .iter()
+            .map(|(name, price)| (name.as_str(), *price));
+        PRICES
+            .iter()
+            .copied()
+            .chain(overrides)
+            .filter(|(name, _)| model.starts_with(name))
+            .max_by_key(|(name, _)| name.len())
+            .map(|(_, price)| price)
+    }
+
+    pub fn cost(&self, model: &str, usage: &Usage) -> Option<f64> {
+        self.find(model).map(|price| price.cost(usage))
+    }
+}
+
+/// Tokens used since octo started, per model, and what they cost.
+#[derive(Debug, Default)]
+pub struct Tally {
+    prices: Prices,
+    models: BTreeMap<String, Usage>,
+}
+
+impl Tally {
+    pub fn new(prices: Prices) -> Self {
+        Tally {
+            prices,
+            models: BTreeMap::new(),
+        }
+    }
+
+    /// Count the usage of a reply, returning its cost if the price is known.
+    pub fn add(&mut self, model: &str, usage: Usage) -> Option<f64> {
+        *self.models.entry(model.to_string()).or_default() += usage;
+        self.prices.cost(model, &usage)
+    }
+
+    /// The usage of each model, with its cost if the price is known.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Usage, Option<f64>)> {
+        self.models
+            .iter()
+            .map(|(model, usage)| (model.as_str(), usage, self.prices.cost(model, usage)))
+    }
+
+    pub fn total(&self) -> Usage {
+        let mut total = Usage::default();
+        for usage in self.models.values() {
+            total += *usage;
+        }
+        total
+    }
+
+    /// The estimated cost, and whether some models have no known price.
+    pub fn cost(&self) -> (f64, bool) {
+        let mut cost = 0.0;
+        let mut unknown = false;
+        for (_, _, model_cost) in self.iter() {
+            match model_cost {
+                Some(model_cost) => cost += model_cost,
+                None => unknown = true,
+            }
+        }
+        (cost, unknown)
+    }
+}
+
+/// Format a cost in dollars, with more digits for small amounts.
+pub fn dollars(cost: f64) -> String {
+    if cost < 0.01 {
+        format!("${cost:.4}")
+    } else {
+        format!("${cost:.2}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(prompt_tokens: i64, completion_tokens: i64) -> Usage {
+        Usage {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+        }
+    }
+
+    #[test]
+    fn test_costs() {
+        let mut prices = Prices::default();
+        assert_eq!(
+            prices.find("gpt-4-turbo-2024-04-09"),
+            Some(price(10.0, 30.0))
+        );
+        assert_eq!(prices.find("gpt-4-0613"), Some(price(30.0, 60.0)));
+        assert_eq!(prices.find("llama3"), None);
+
+        prices
+            .overrides
+            .insert("llama3".to_string(), price(0.1, 0.2));
+        prices
+            .overrides
+            .insert("gpt-4".to_string(), price(1.0, 2.0));
+        assert_eq!(prices.find("llama3:8b"), Some(price(0.1, 0.2)));
+        assert_eq!(prices.find("gpt-4"), Some(price(1.0, 2.0)));
+
+        let mut tally = Tally::new(prices);
+        assert_eq!(tally.add("gpt-4", usage(1_000_000, 0)), Some(1.0));
+        tally.add("gpt-4", usage(0, 500_000));
+        assert_eq!(tally.add("mystery", usage(10, 10)), None);
+
+        assert_eq!(tally.total(), usage(1_000_010, 500_010));
+        assert_eq!(tally.cost(), (2.0, true));
+        assert_eq!(dollars(0.00123), "$0.0012");
+        assert_eq!(dollars(2.0), "$2.00");
+    }
+}
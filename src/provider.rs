@@ -0,0 +1,289 @@
+use anyhow::{anyhow, Result};
+use reqwest::RequestBuilder;
+
+use crate::anthropic::Anthropic;
+use crate::chat::{Chat, Settings};
+use crate::conversation::Conversation;
+use crate::gemini::Gemini;
+use crate::ollama::Ollama;
+
+/// How the API key is sent to the provider.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Auth {
+    /// `Authorization: Bearer <key>`
+    Bearer,
+    /// The key as the value of the given header.
+    Header(&'static str),
+    /// No key required, e.g. for local servers. If one is given anyway,
+    /// it is sent as a bearer token.
+    None,
+}
+
+impl Auth {
+    pub fn apply(&self, builder: RequestBuilder, api_key: &str) -> RequestBuilder {
+        match self {
+            Auth::Bearer => builder.bearer_auth(api_key),
+            Auth::Header(name) => builder.header(*name, api_key),
+            Auth::None => builder,
+        }
+    }
+}
+
+/// The REST API shape spoken by the provider.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Dialect {
+    /// OpenAI chat completions, also spoken by most vendors.
+    OpenAI,
+    Gemini,
+    Anthropic,
+    /// Ollama's `/api/chat`, streaming newline delimited JSON.
+    Ollama,
+}
+
+/// Everything needed to connect to a provider.
+#[derive(Debug)]
+pub struct Provider {
+    /// Name used on the command line and in session files.
+    pub name: &'static str,
+
+    /// Environment variable holding the API key.
+    pub env: &'static str,
+
+    /// Default endpoint.
+    pub url: &'static str,
+
+    /// Default model, if empty the first one listed by the server is used.
+    pub model: &'static str,
+
+    pub auth: Auth,
+    pub dialect: Dialect,
+
+    /// Request fields the provider expects under a different name.
+    pub renames: &'static [(&'static str, &'static str)],
+
+    /// Whether the provider reports the token usage of streams when asked
+    /// with `stream_options`, for the OpenAI dialect.
+    pub stream_usage: bool,
+}
+
+pub const PROVIDERS: &[Provider] = &[
+    Provider {
+        name: "open-ai",
+        env: "OPENAI_API_KEY",
+        url: "https://api.openai.com/v1/chat/completions",
+        model: "gpt-3.5-turbo-1106",
+        auth: Auth::Bearer,
+        dialect: Dialect::OpenAI,
+        renames: &[],
+        stream_usage: true,
+    },
+    Provider {
+        name: "together-ai",
+        env: "TOGETHERAI_API_KEY",
+        url: "https://api.together.xyz/v1/chat/completions",
+        model: "mistralai/Mixtral-8x7B-Instruct-v0.1",
+        auth: Auth::Bearer,
+        dialect: Dialect::OpenAI,
+        renames: &[],
+        stream_usage: false,
+    },
+    Provider {
+        name: "mistral-ai",
+        env: "MISTRALAI_API_KEY",
+        url: "https://api.mistral.ai/v1/chat/completions",
+        model: "mistral-medium",
+        auth: Auth::Bearer,
+        dialect: Dialect::OpenAI,
+        renames: &[("seed", "random_seed")],
+        stream_usage: false,
+    },
+    Provider {
+        name: "gemini",
+        env: "GEMINI_API_KEY",
+        url: "https://generativelanguage.googleapis.com/v1beta",
+        model: "gemini-pro",
+        auth: Auth::Header("x-goog-api-key"),
+        dialect: Dialect::Gemini,
+        renames: &[],
+        stream_usage: false,
+    },
+    Provider {
+        name: "anthropic",
+        env: "ANTHROPIC_API_KEY",
+        url: "https://api.anthropic.com/v1/messages",
+        model: "claude-3-haiku-20240307",
+        auth: Auth::Header("x-api-key"),
+        dialect: Dialect::Anthropic,
+        renames: &[],
+        stream_usage: false,
+    },
+    Provider {
+        name: "ollama",
+        env: "OLLAMA_API_KEY",
+        url: "http://localhost:11434/api/chat",
+        model: "",
+        auth: Auth::None,
+        dialect: Dialect::Ollama,
+        renames: &[],
+        stream_usage: false,
+    },
+    Provider {
+        name: "local",
+        env: "LOCAL_API_KEY",
+        url: "http://localhost:8080/v1/chat/completions",
+        model: "",
+        auth: Auth::None,
+        dialect: Dialect::OpenAI,
+        renames: &[],
+        stream_usage: false,
+    },
+];
+
+pub fn names() -> Vec<&'static str> {
+    PROVIDERS.iter().map(|provider| provider.name).collect()
+}
+
+pub fn find(name: &str) -> Result<&'static Provider> {
+    PROVIDERS
+        .iter()
+        .find(|provider| provider.name == name)
+        .ok_or(anyhow!("Unknown provider {name}"))
+}
+
+impl Provider {
+    /// Start a conversation, using the provider defaults for anything not given.
+    pub fn connect(
+        &self,
+        api_key: Option<String>,
+        url: Option<String>,
+        model: Option<String>,
+        settings: &Settings,
+    ) -> Result<Box<dyn Conversation>> {
+        let (api_key, auth) = match (api_key.or(std::env::var(self.env).ok()), self.auth) {
+            (Some(api_key), Auth::None) => (api_key, Auth::Bearer),
+            (Some(api_key), auth) => (api_key, auth),
+            (None, Auth::None) => (String::new(), Auth::None),
+            (None, _) => Err(anyhow!(
+                "Missing API key, set {} or use --api-key",
+                self.env
+            ))?,
+        };
+        let url = url.unwrap_or(self.url.to_string());
+        let model = model.unwrap_or(self.model.to_string());
+
+        let chat: Box<dyn Conversation> = match self.dialect {
+            Dialect::OpenAI => {
+                let mut chat = Chat::new(&api_key, &url, &model, settings)?.auth(auth);
+                for (field, name) in self.renames {
+                    chat = chat.rename(field, name);
+                }
+                if self.stream_usage {
+                    chat = chat.stream_usage();
+                }
+                Box::new(chat)
+            }
+            Dialect::Gemini => Box::new(Gemini::new(&api_key, &url, &model, settings).auth(auth)),
+            Dialect::Anthropic => {
+                Box::new(Anthropic::new(&api_key, &url, &model, settings).auth(auth))
+            }
+            Dialect::Ollama => Box::new(Ollama::new(&api_key, &url, &model, settings)?.auth(auth)),
+        };
+
+        Ok(chat)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+    use wiremock::{matchers::any, Mock, MockServer, ResponseTemplate};
+
+    use crate::conversation::Role;
+    use crate::tools::Tools;
+
+    #[test]
+    fn test_find_providers() {
+        for provider in PROVIDERS {
+            assert_eq!(find(provider.name).unwrap().name, provider.name);
+        }
+        assert_eq!(names().len(), PROVIDERS.len());
+        assert!(find("nope").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_connect_providers() {
+        for provider in PROVIDERS {
+            let server = MockServer::start().await;
+            Mock::given(any())
+                .respond_with(ResponseTemplate::new(400))
+                .mount(&server)
+                .await;
+
+            let mut chat = provider
+                .connect(
+                    Some("key".to_string()),
+                    Some(server.uri()),
+                    Some("model".to_string()),
+                    &Settings {
+                        seed: Some(42),
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+            assert_eq!(chat.model(), "model");
+
+            // Only the OpenAI dialect can call tools, the others refuse them
+            let mut tools = Tools::default();
+            tools.register("noop", "Do nothing", json!({}), |_| Ok(String::new()));
+            assert_eq!(
+                chat.set_tools(tools).is_ok(),
+                provider.dialect == Dialect::OpenAI,
+                "{}",
+                provider.name
+            );
+            assert!(chat.set_tools(Tools::default()).is_ok());
+            let _ = chat.build(Role::User, "Hi").send(&|_| {}).await;
+
+            let requests = server.received_requests().await.unwrap();
+            let request = &requests[0];
+            let header = |name: &str| {
+                request
+                    .headers
+                    .iter()
+                    .find(|(header, _)| header.as_str() == name)
+                    .map(|(_, values)| values.last().as_str().to_string())
+            };
+
+            // Keyless providers send a key given anyway as a bearer token
+            match provider.auth {
+                Auth::Bearer | Auth::None => {
+                    assert_eq!(header("authorization").as_deref(), Some("Bearer key"))
+                }
+                Auth::Header(name) => {
+                    assert_eq!(header(name).as_deref(), Some("key"), "{}", provider.name);
+                    assert_eq!(header("authorization"), None, "{}", provider.name);
+                }
+            }
+
+            // Each dialect is told apart by the shape of its request
+            let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+            let dialect = if request.url.path().ends_with(":generateContent") {
+                Dialect::Gemini
+            } else if header("anthropic-version").is_some() {
+                Dialect::Anthropic
+            } else if body.get("options").is_some() {
+                Dialect::Ollama
+            } else {
+                Dialect::OpenAI
+            };
+            assert_eq!(dialect, provider.dialect, "{}", provider.name);
+
+            for (field, name) in provider.renames {
+                assert_eq!(body.get(field), None, "{}", provider.name);
+                assert_eq!(body[name], 42, "{}", provider.name);
+            }
+        }
+    }
+}
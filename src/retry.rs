@@ -0,0 +1,247 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use anyhow::Result;
+
+use crate::conversation::{Callback, Conversation, State};
+use crate::error::OctoError;
+
+/// Longest wait between two attempts, unless the server asks for more.
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// Longest wait the server may ask for. Rather than block for longer, the
+/// error is left to the user to try again later.
+const MAX_WAIT: Duration = Duration::from_secs(300);
+
+/// How requests failing for a transient reason are tried again.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Retry {
+    /// How many times to try again, 0 disables retries.
+    pub attempts: u32,
+
+    /// Wait before the first retry, doubled for each of the next ones.
+    pub delay: Duration,
+
+    /// Whether to try again once part of the reply was shown, which is then
+    /// shown again from the start.
+    pub partial: bool,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Retry {
+            attempts: 3,
+            delay: Duration::from_secs(1),
+            partial: false,
+        }
+    }
+}
+
+impl Retry {
+    /// Exponential backoff, with a random jitter of up to half the delay so
+    /// that clients limited together don't retry together.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self
+            .delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(MAX_DELAY);
+        let random = RandomState::new().build_hasher().finish();
+        let jitter = (random >> 11) as f64 / (1u64 << 53) as f64;
+        delay.mul_f64(1.0 - jitter / 2.0)
+    }
+}
+
+/// Send the conversation, trying again after transient failures as set in
+/// its settings. The wait is counted down through the callback, in seconds.
+/// Tool calls answered before a failure stay in the history, and are not
+/// made again.
+pub async fn send(chat: &mut dyn Conversation, f: Callback<'_>) -> Result<()> {
+    let retry = chat.settings().retry;
+
+    let mut attempt = 0;
+    loop {
+        let shown = AtomicBool::new(false);
+        let result = chat
+            .send(&|state| {
+                if let State::Message(_) = state {
+                    shown.store(true, Ordering::Relaxed);
+                }
+                f(state)
+            })
+            .await;
+
+        let error = match result {
+            Err(error) => error,
+            Ok(()) => return Ok(()),
+        };
+        let Some(failure) = error
+            .downcast_ref::<OctoError>()
+            .filter(|failure| failure.is_transient())
+        else {
+            return Err(error);
+        };
+        if attempt >= retry.attempts || (shown.into_inner() && !retry.partial) {
+            return Err(error);
+        }
+
+        let reason = failure.to_string();
+        let mut left = failure
+            .retry_after()
+            .unwrap_or_else(|| retry.backoff(attempt));
+        if left > MAX_WAIT {
+            return Err(error);
+        }
+        while !left.is_zero() {
+            let seconds = left.as_secs_f64().ceil() as u64;
+            f(State::Retry(&reason, seconds));
+
+            let step = left.saturating_sub(Duration::from_secs(seconds - 1));
+            tokio::time::sleep(step).await;
+            left -= step;
+        }
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+    use wiremock::{matchers::method, Mock, MockServer, ResponseTemplate};
+
+    use crate::chat::{Chat, Settings};
+    use crate::conversation::Role;
+
+    #[tokio::test]
+    async fn test_retry() {
+        // Fails twice, then replies
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .respond_with(
+                ResponseTemplate::new(429).insert_header("x-ratelimit-reset-requests", "20ms"),
+            )
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(
+                include_str!("../tests/fixtures/openai/answer.sse"),
+                "text/event-stream",
+            ))
+            .mount(&server)
+            .await;
+
+        let settings = Settings {
+            retry: Retry {
+                delay: Duration::from_millis(10),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut chat = Chat::new("key", &server.uri(), "model", &settings).unwrap();
+        chat.build(Role::User, "Hi");
+
+        let retries = Mutex::new(vec![]);
+        send(&mut chat, &|state| {
+            if let State::Retry(reason, _) = state {
+                retries.lock().unwrap().push(reason.to_string());
+            }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(
+            retries.into_inner().unwrap(),
+            [
+                "Invalid status code: 503 Service Unavailable",
+                "Invalid status code: 429 Too Many Requests"
+            ]
+        );
+        assert_eq!(chat.history().len(), 2);
+
+        // Client errors are not retried
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(401))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let mut chat = Chat::new("key", &server.uri(), "model", &settings).unwrap();
+        chat.build(Role::User, "Hi");
+        assert!(send(&mut chat, &|_| {}).await.is_err());
+
+        // Nor are waits longer than the cap, left to the user
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(429).insert_header("retry-after", "86400"))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let mut chat = Chat::new("key", &server.uri(), "model", &settings).unwrap();
+        chat.build(Role::User, "Hi");
+        let error = send(&mut chat, &|_| {}).await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<OctoError>(),
+            Some(OctoError::RateLimit(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_retry_dropped() {
+        // Drops the first connection without a word, then replies
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            drop(socket);
+
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = vec![0; 64 * 1024];
+            let _ = socket.read(&mut request).await;
+            let body = include_str!("../tests/fixtures/openai/answer.sse");
+            let response = format!(
+                "HTTP/1.1 200 OK\r\ncontent-type: text/event-stream\r\n\
+                content-length: {}\r\n\r\n{body}",
+                body.len()
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+        });
+
+        let settings = Settings {
+            retry: Retry {
+                delay: Duration::from_millis(10),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut chat = Chat::new("key", &format!("http://{address}"), "model", &settings).unwrap();
+        chat.build(Role::User, "Hi");
+
+        let retries = Mutex::new(0);
+        send(&mut chat, &|state| {
+            if let State::Retry(..) = state {
+                *retries.lock().unwrap() += 1;
+            }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(retries.into_inner().unwrap(), 1);
+        assert_eq!(chat.history()[1].content.as_deref(), Some("It is 3"));
+    }
+}
@@ -0,0 +1,92 @@
+use std::{fs, path::Path};
+
+use anyhow::{anyhow, Result};
+use jsonschema::JSONSchema;
+use serde_json::Value;
+
+use crate::conversation::Conversation;
+
+/// Read a JSON schema, checking it is valid before sending it anywhere.
+pub fn load(path: &Path) -> Result<Value> {
+    let content =
+        fs::read_to_string(path).map_err(|e| anyhow!("Cannot read {}: {e}", path.display()))?;
+    let schema = serde_json::from_str(&content)
+        .map_err(|e| anyhow!("{} is not valid JSON: {e}", path.display()))?;
+
+    JSONSchema::compile(&schema)
+        .map_err(|e| anyhow!("{} is not a valid schema: {e}", path.display()))?;
+    Ok(schema)
+}
+
+/// Parse a JSON mode reply and validate it against the schema, if any.
+/// Models sometimes wrap the object in a Markdown fence, which is ignored.
+pub fn validate(reply: &str, schema: Option<&Value>) -> Result<Value, Vec<String>> {
+    let text = reply.trim();
+    let text = text
+        .strip_prefix("```json")
+        .or_else(|| text.strip_prefix("```"))
+        .and_then(|text| text.strip_suffix("```"))
+        .unwrap_or(text);
+
+    let value: Value =
+        serde_json::from_str(text).map_err(|e| vec![format!("Invalid JSON: {e}")])?;
+
+    let Some(schema) = schema else {
+        return Ok(value);
+    };
+    let compiled = JSONSchema::compile(schema).map_err(|e| vec![e.to_string()])?;
+    if let Err(errors) = compiled.validate(&value) {
+        return Err(errors
+            .map(|error| match error.instance_path.to_string() {
+                path if path.is_empty() => error.to_string(),
+                path => format!("{path}: {error}"),
+            })
+            .collect());
+    }
+    Ok(value)
+}
+
+/// Validate the last reply of the conversation, in JSON mode.
+pub fn check(chat: &dyn Conversation) -> Result<Value, Vec<String>> {
+    let reply = chat
+        .history()
+        .last()
+        .and_then(|data| data.content.as_deref());
+    validate(reply.unwrap_or_default(), chat.settings().schema.as_ref())
+}
+
+/// The message asking the model to fix an invalid reply.
+pub fn retry(errors: &[String]) -> String {
+    format!(
+        "Your reply is not valid, fix these errors and reply again with the JSON only:\n- {}",
+        errors.join("\n- ")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    fn test_validate_reply() {
+        let schema = json!({
+            "type": "object",
+            "properties": { "port": { "type": "integer" } },
+            "required": ["port"]
+        });
+
+        assert_eq!(
+            validate("```json\n{\"port\": 80}\n```", Some(&schema)).unwrap(),
+            json!({ "port": 80 })
+        );
+        assert_eq!(
+            validate(r#"{"port": "80"}"#, Some(&schema)).unwrap_err(),
+            [r#"/port: "80" is not of type "integer""#]
+        );
+        assert!(validate("{}", Some(&schema)).unwrap_err()[0].contains("required"));
+        assert!(validate("port: 80", None).unwrap_err()[0].starts_with("Invalid JSON"));
+        assert_eq!(validate("[1]", None).unwrap(), json!([1]));
+    }
+}
@@ -0,0 +1,104 @@
+use std::{
+    fs,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::chat::{Data, Settings};
+
+/// Schema version written by this build, bump it whenever the format
+/// changes and teach `migrate` how to upgrade the previous one.
+pub const VERSION: u64 = 1;
+
+/// A conversation saved to disk, with what's needed to resume it.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Session {
+    pub version: u64,
+    pub provider: String,
+    pub model: String,
+    pub settings: Settings,
+
+    /// Seconds since the UNIX epoch.
+    pub created: u64,
+    pub updated: u64,
+
+    pub history: Vec<Data>,
+}
+
+impl Session {
+    pub fn new(provider: &str, model: &str, settings: &Settings, history: &[Data]) -> Self {
+        let now = now();
+        Session {
+            version: VERSION,
+            provider: provider.to_string(),
+            model: model.to_string(),
+            settings: settings.clone(),
+            created: now,
+            updated: now,
+            history: history.to_vec(),
+        }
+    }
+
+    pub fn save(&mut self, path: &Path) -> Result<()> {
+        self.updated = now();
+
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let value = serde_json::from_str(&fs::read_to_string(path)?)?;
+        Ok(serde_json::from_value(migrate(value)?)?)
+    }
+}
+
+/// Upgrade a session document, one version at a time, to the current schema.
+/// Version 1 is the first one octo wrote, there is nothing older to upgrade.
+fn migrate(value: Value) -> Result<Value> {
+    let version = value["version"]
+        .as_u64()
+        .ok_or(anyhow!("Session file has no schema version"))?;
+
+    match version {
+        VERSION => Ok(value),
+        _ => Err(anyhow!(
+            "Session schema version {version} is not supported, this build reads {VERSION}"
+        )),
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    fn test_migrate_sessions() {
+        let current = serde_json::to_value(Session::new(
+            "mistral-ai",
+            "mistral-medium",
+            &Settings::default(),
+            &[],
+        ))
+        .unwrap();
+        assert_eq!(migrate(current.clone()).unwrap(), current);
+
+        assert!(migrate(json!({ "version": VERSION + 1 })).is_err());
+        assert!(migrate(json!([{ "role": "user", "content": "Hello" }])).is_err());
+    }
+}
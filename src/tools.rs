@@ -0,0 +1,180 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use serde_json::Value;
+
+/// Runs a tool call, receiving the arguments generated by the model and
+/// returning the content of the tool message sent back.
+#[async_trait]
+pub trait Handler: Send + Sync {
+    async fn call(&self, arguments: Value) -> Result<String>;
+}
+
+#[async_trait]
+impl<F> Handler for F
+where
+    F: Fn(Value) -> Result<String> + Send + Sync,
+{
+    async fn call(&self, arguments: Value) -> Result<String> {
+        self(arguments)
+    }
+}
+
+/// A function the model may call.
+#[derive(Clone)]
+pub struct Tool {
+    pub name: String,
+    pub description: String,
+
+    /// JSON schema of the arguments object.
+    pub parameters: Value,
+    pub handler: Arc<dyn Handler>,
+}
+
+/// The tools registered for a conversation.
+#[derive(Clone)]
+pub struct Tools {
+    tools: Vec<Tool>,
+
+    /// How many times in a row the model can call tools before giving up
+    /// on a final answer.
+    pub max_iterations: usize,
+}
+
+impl Default for Tools {
+    fn default() -> Self {
+        Tools {
+            tools: vec![],
+            max_iterations: 8,
+        }
+    }
+}
+
+impl Tools {
+    pub fn new(max_iterations: usize) -> Self {
+        Tools {
+            max_iterations,
+            ..Default::default()
+        }
+    }
+
+    /// Add a tool, replacing any other tool with the same name.
+    pub fn register(
+        &mut self,
+        name: &str,
+        description: &str,
+        parameters: Value,
+        handler: impl Handler + 'static,
+    ) -> &mut Self {
+        self.tools.retain(|tool| tool.name != name);
+        self.tools.push(Tool {
+            name: name.to_string(),
+            description: description.to_string(),
+            parameters,
+            handler: Arc::new(handler),
+        });
+        self
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Tool> {
+        self.tools.iter()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.tools.iter().any(|tool| tool.name == name)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tools.is_empty()
+    }
+
+    /// Run the named tool. Failures are reported back to the model, which
+    /// may fix its arguments and try again, rather than ending the turn.
+    pub async fn call(&self, name: &str, arguments: &str) -> String {
+        let result = async {
+            let tool = self
+                .tools
+                .iter()
+                .find(|tool| tool.name == name)
+                .ok_or(anyhow!("Unknown tool {name}"))?;
+
+            let arguments = match arguments.trim() {
+                "" => Value::Object(Default::default()),
+                arguments => serde_json::from_str(arguments)
+                    .map_err(|e| anyhow!("Invalid JSON arguments: {e}"))?,
+            };
+
+            tool.handler.call(arguments).await
+        }
+        .await;
+
+        result.unwrap_or_else(|error| format!("Error: {error}"))
+    }
+}
+
+/// The process group of a command run by a tool. Killed when dropped, e.g.
+/// on timeout, unless released once the command ended, so that what the
+/// command started in the background goes along with it.
+pub(crate) struct Group(Option<u32>);
+
+impl Group {
+    /// Spawn the command as the leader of a process group of its own.
+    pub fn spawn(
+        command: &mut tokio::process::Command,
+    ) -> std::io::Result<(tokio::process::Child, Group)> {
+        #[cfg(unix)]
+        command.process_group(0);
+        let child = command.spawn()?;
+        let group = Group(child.id());
+        Ok((child, group))
+    }
+
+    /// The command ended, leave the group alone.
+    pub fn release(&mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for Group {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Some(id) = self.0.and_then(|id| libc::pid_t::try_from(id).ok()) {
+            // SAFETY: killpg takes no pointers, failures such as an already
+            // gone group are of no concern
+            unsafe {
+                libc::killpg(id, libc::SIGKILL);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_call_tools() {
+        let mut tools = Tools::default();
+        tools.register(
+            "add",
+            "Add two numbers",
+            json!({ "type": "object" }),
+            |arguments: Value| {
+                let a = arguments["a"].as_i64().ok_or(anyhow!("a is missing"))?;
+                let b = arguments["b"].as_i64().ok_or(anyhow!("b is missing"))?;
+                Ok((a + b).to_string())
+            },
+        );
+
+        assert_eq!(tools.call("add", r#"{"a": 1, "b": 2}"#).await, "3");
+        assert_eq!(tools.call("add", "{}").await, "Error: a is missing");
+        assert!(tools
+            .call("add", "{")
+            .await
+            .starts_with("Error: Invalid JSON"));
+        assert_eq!(tools.call("sub", "").await, "Error: Unknown tool sub");
+    }
+}
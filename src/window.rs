@@ -0,0 +1,500 @@
+use std::fmt;
+
+use anyhow::Result;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use tiktoken_rs::{
+    cl100k_base_singleton, o200k_base_singleton, p50k_base_singleton, r50k_base_singleton,
+    tokenizer::{get_tokenizer, Tokenizer},
+};
+
+use crate::chat::{Data, Settings};
+use crate::conversation::{Conversation, Role, Usage};
+use crate::retry;
+
+/// Rough number of bytes per token, for models without a known tokenizer.
+const BYTES_PER_TOKEN: usize = 4;
+
+/// Tokens taken by each message on top of its content, for the role and
+/// the separators.
+const TOKENS_PER_MESSAGE: usize = 4;
+
+/// Room left for the summary of the dropped turns.
+const SUMMARY_TOKENS: usize = 400;
+
+/// Context window sizes, in tokens, matched by model name prefix.
+const WINDOWS: &[(&str, usize)] = &[
+    ("gpt-3.5-turbo", 16_385),
+    ("gpt-4", 8_192),
+    ("gpt-4-32k", 32_768),
+    ("gpt-4-turbo", 128_000),
+    ("gpt-4-1106-preview", 128_000),
+    ("gpt-4-0125-preview", 128_000),
+    ("gpt-4o", 128_000),
+    ("claude-2", 100_000),
+    ("claude-3", 200_000),
+    ("gemini-pro", 30_720),
+    ("gemini-1.0-pro", 30_720),
+    ("gemini-1.5", 1_048_576),
+    ("mistral-", 32_000),
+    ("open-mistral", 32_000),
+    ("open-mixtral", 32_000),
+    ("mistralai/Mixtral-8x7B", 32_768),
+    ("llama2", 4_096),
+    ("llama3", 8_192),
+];
+
+/// The number of tokens of a text, exact for the OpenAI models and
+/// estimated from its length for the others.
+pub fn count(model: &str, text: &str) -> usize {
+    let bpe = match get_tokenizer(model) {
+        Some(Tokenizer::O200kBase) => o200k_base_singleton(),
+        Some(Tokenizer::Cl100kBase) => cl100k_base_singleton(),
+        Some(Tokenizer::P50kBase) => p50k_base_singleton(),
+        Some(Tokenizer::R50kBase | Tokenizer::Gpt2) => r50k_base_singleton(),
+        _ => return text.len().div_ceil(BYTES_PER_TOKEN),
+    };
+    let tokens = bpe.lock().encode_ordinary(text).len();
+    tokens
+}
+
+/// The number of tokens of the messages, tool calls included.
+pub fn tokens(model: &str, history: &[Data]) -> usize {
+    history
+        .iter()
+        .map(|data| {
+            let calls = data
+                .tool_calls
+                .as_ref()
+                .and_then(|calls| serde_json::to_string(calls).ok());
+            let content = data.content.as_deref().unwrap_or_default();
+            TOKENS_PER_MESSAGE + count(model, content) + count(model, &calls.unwrap_or_default())
+        })
+        .sum()
+}
+
+/// The context window of a model, from the longest matching prefix.
+pub fn size(model: &str) -> Option<usize> {
+    WINDOWS
+        .iter()
+        .filter(|(name, _)| model.starts_with(name))
+        .max_by_key(|(name, _)| name.len())
+        .map(|(_, size)| *size)
+}
+
+/// Format a number of tokens, shortened to thousands.
+pub fn short(tokens: usize) -> String {
+    if tokens < 1000 {
+        tokens.to_string()
+    } else if tokens < 10_000 {
+        format!("{:.1}k", tokens as f64 / 1000.0)
+    } else {
+        format!("{}k", tokens / 1000)
+    }
+}
+
+/// What to do with the oldest turns once the history outgrows the window.
+#[derive(ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Policy {
+    /// Drop them, the system prompt is always kept
+    #[default]
+    Drop,
+
+    /// Replace them by a summary written by the model
+    Summarize,
+
+    /// Send the history as is, and let the provider complain
+    Off,
+}
+
+/// How the history was made to fit.
+#[derive(Debug, PartialEq)]
+pub enum Fitted {
+    Dropped(usize),
+
+    /// The oldest turns were replaced by a summary, which took the usage of
+    /// its own request.
+    Summarized {
+        count: usize,
+        model: String,
+        usage: Option<Usage>,
+    },
+
+    /// The last turn alone is over budget, the history is left as it is and
+    /// sent even though it does not fit.
+    Overflow {
+        tokens: usize,
+        budget: usize,
+    },
+}
+
+impl fmt::Display for Fitted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fitted::Dropped(count) => {
+                write!(
+                    f,
+                    "Dropped the {count} oldest message(s) to fit the context window"
+                )
+            }
+            Fitted::Summarized { count, usage, .. } => {
+                write!(
+                    f,
+                    "Summarized the {count} oldest message(s) to fit the context window"
+                )?;
+                if let Some(usage) = usage {
+                    write!(
+                        f,
+                        ", {} + {} tokens",
+                        usage.prompt_tokens, usage.completion_tokens
+                    )?;
+                }
+                Ok(())
+            }
+            Fitted::Overflow { tokens, budget } => {
+                write!(
+                    f,
+                    "The last message alone takes {} of the {} tokens left in the context window, \
+                    the conversation is sent as is and may be rejected",
+                    short(*tokens),
+                    short(*budget)
+                )
+            }
+        }
+    }
+}
+
+/// Asks the model to summarize the turns about to be dropped.
+const SUMMARIZE: &str = "Summarize the conversation below in at most 200 words. \
+Keep the facts, decisions and open questions needed to carry on with it.";
+
+/// Stands for the summarized turns in the history, answered by the summary.
+const RECALL: &str = "Summarize our conversation so far.";
+
+/// Keeps the history within the context window of the model.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Window {
+    /// Overrides the known window size of the model.
+    size: Option<usize>,
+    policy: Policy,
+}
+
+impl Window {
+    pub fn new(size: Option<usize>, policy: Policy) -> Self {
+        Window { size, policy }
+    }
+
+    /// The window size of the current model, if known.
+    pub fn size(&self, chat: &dyn Conversation) -> Option<usize> {
+        self.size.or_else(|| size(chat.model()))
+    }
+
+    /// Tokens used by the history, and the window size if known.
+    pub fn usage(&self, chat: &dyn Conversation) -> (usize, Option<usize>) {
+        (tokens(chat.model(), chat.history()), self.size(chat))
+    }
+
+    /// Tokens the history can use, leaving room for the reply.
+    fn budget(&self, chat: &dyn Conversation) -> Option<usize> {
+        let reply = usize::try_from(chat.settings().max_tokens).unwrap_or_default();
+        self.size(chat).map(|size| size.saturating_sub(reply))
+    }
+
+    /// Drop or summarize the oldest turns until the history fits, before it
+    /// is sent. The system prompt and the last turn are always kept.
+    pub async fn fit(&self, chat: &mut dyn Conversation) -> Result<Option<Fitted>> {
+        let Some(budget) = self.budget(chat) else {
+            return Ok(None);
+        };
+        let model = chat.model().to_string();
+        let history = chat.history();
+        if self.policy == Policy::Off || tokens(&model, history) <= budget {
+            return Ok(None);
+        }
+
+        // Turns start with a user message, followed by the replies and tool calls
+        let start = usize::from(chat.system().is_some());
+        let turns = (start..history.len())
+            .filter(|&index| history[index].role.as_deref() == Some("user"))
+            .collect::<Vec<_>>();
+        let Some(&last) = turns.last() else {
+            return Ok(None);
+        };
+
+        let reserve = match self.policy {
+            Policy::Summarize => SUMMARY_TOKENS,
+            _ => 0,
+        };
+        let system = tokens(&model, &history[..start]);
+        let keep = turns[1..]
+            .iter()
+            .copied()
+            .find(|&index| system + tokens(&model, &history[index..]) + reserve <= budget);
+
+        // Not even the last turn fits, dropping the others would lose them
+        // for nothing, the history is left for the provider to judge
+        let Some(keep) = keep else {
+            let used = system + tokens(&model, &history[last..]);
+            return Ok(Some(Fitted::Overflow {
+                tokens: used,
+                budget,
+            }));
+        };
+
+        let mut fitted = history[..start].to_vec();
+        let summary = match self.policy {
+            Policy::Summarize => summarize(chat, start, keep, budget).await?,
+            _ => None,
+        };
+        let result = match summary {
+            Some((summary, usage)) => {
+                fitted.push(Data::new(Role::User, RECALL));
+                fitted.push(Data::new(Role::Assistant, &summary));
+                let model = chat.metadata().model.clone().unwrap_or(model);
+                Fitted::Summarized {
+                    count: keep - start,
+                    model,
+                    usage,
+                }
+            }
+            None => Fitted::Dropped(keep - start),
+        };
+        fitted.extend_from_slice(&chat.history()[keep..]);
+        chat.restore(fitted);
+
+        Ok(Some(result))
+    }
+}
+
+/// Have the model summarize the messages in `start..end`, as a side
+/// conversation without tools, leaving the history, settings and tools as
+/// they were. The transcript keeps the newest messages within the budget,
+/// the older ones are left out, and without room for any of them nothing is
+/// asked. Returns the summary and the usage of its request.
+async fn summarize(
+    chat: &mut dyn Conversation,
+    start: usize,
+    end: usize,
+    budget: usize,
+) -> Result<Option<(String, Option<Usage>)>> {
+    let history = chat.history().to_vec();
+    let model = chat.model().to_string();
+    let prompt = tokens(&model, &[Data::new(Role::System, SUMMARIZE)]);
+    let mut room = budget.saturating_sub(prompt + TOKENS_PER_MESSAGE);
+    let mut lines = Vec::new();
+    for data in history[start..end].iter().rev() {
+        let (Some(role), Some(content)) = (data.role.as_deref(), data.content.as_deref()) else {
+            continue;
+        };
+        let line = format!("{role}: {content}");
+        let Some(left) = room.checked_sub(count(&model, &line) + 1) else {
+            break;
+        };
+        room = left;
+        lines.push(line);
+    }
+    if lines.is_empty() {
+        return Ok(None);
+    }
+    lines.reverse();
+    let transcript = lines.join("\n\n");
+
+    // Tools could run during the side conversation, e.g. the shell
+    let tools = chat.take_tools();
+    let settings = chat.settings().clone();
+    *chat.settings_mut() = Settings {
+        json: false,
+        schema: None,
+        ..settings.clone()
+    };
+    chat.restore(vec![
+        Data::new(Role::System, SUMMARIZE),
+        Data::new(Role::User, &transcript),
+    ]);
+
+    let result = retry::send(chat, &|_| {}).await;
+    let summary = chat
+        .history()
+        .last()
+        .filter(|data| data.role.as_deref() == Some("assistant"))
+        .and_then(|data| data.content.clone());
+    let usage = chat.metadata().usage;
+
+    chat.restore(history);
+    *chat.settings_mut() = settings;
+    chat.set_tools(tools)?;
+    result?;
+    Ok(Some((summary.unwrap_or_default(), usage)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::chat::Chat;
+
+    #[tokio::test]
+    async fn test_fit_window() {
+        assert_eq!(count("gpt-4", "Hello world"), 2);
+        assert_eq!(count("llama3", "Hello world"), 3);
+        assert_eq!(size("gpt-4-0613"), Some(8_192));
+        assert_eq!(size("gpt-4o-2024-05-13"), Some(128_000));
+        assert_eq!(size("mystery"), None);
+        assert_eq!(short(1_234), "1.2k");
+
+        let settings = Settings {
+            max_tokens: 10,
+            ..Default::default()
+        };
+        let mut chat = Chat::new("key", "http://localhost", "gpt-4", &settings).unwrap();
+        chat.set_system("Be brief");
+        for turn in ["one", "two", "three"] {
+            chat.build(Role::User, &format!("Question {turn}"));
+            chat.build(Role::Assistant, &format!("Answer {turn}"));
+        }
+        chat.build(Role::User, "Last question");
+
+        // Plenty of room
+        let window = Window::new(Some(1_000), Policy::Drop);
+        assert_eq!(window.fit(&mut chat).await.unwrap(), None);
+        assert_eq!(chat.history().len(), 8);
+
+        let window = Window::new(Some(40), Policy::Drop);
+        assert_eq!(
+            window.fit(&mut chat).await.unwrap(),
+            Some(Fitted::Dropped(4))
+        );
+        let contents = chat
+            .history()
+            .iter()
+            .map(|data| data.content.as_deref().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            contents,
+            [
+                "Be brief",
+                "Question three",
+                "Answer three",
+                "Last question"
+            ]
+        );
+
+        let window = Window::new(Some(40), Policy::Off);
+        chat.build(Role::Assistant, "Last answer");
+        chat.build(Role::User, "Really the last question");
+        assert_eq!(window.fit(&mut chat).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_fit_overflow() {
+        let settings = Settings {
+            max_tokens: 10,
+            ..Default::default()
+        };
+        let mut chat = Chat::new("key", "http://localhost", "gpt-4", &settings).unwrap();
+        chat.set_system("Be brief");
+        chat.build(Role::User, "Question one");
+        chat.build(Role::Assistant, "Answer one");
+        chat.build(Role::User, &"word ".repeat(100));
+
+        // Said so, rather than claiming the history now fits, and without
+        // losing the older turns for nothing
+        let window = Window::new(Some(40), Policy::Summarize);
+        let fitted = window.fit(&mut chat).await.unwrap().unwrap();
+        let (system, last) = (&chat.history()[..1], &chat.history()[3..]);
+        assert_eq!(
+            fitted,
+            Fitted::Overflow {
+                tokens: tokens("gpt-4", system) + tokens("gpt-4", last),
+                budget: 30,
+            }
+        );
+        assert!(fitted.to_string().contains("may be rejected"), "{fitted}");
+        assert_eq!(chat.history().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_fit_summarize() {
+        use serde_json::{json, Value};
+        use wiremock::{matchers::method, Mock, MockServer, ResponseTemplate};
+
+        use crate::tools::Tools;
+
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(
+                include_str!("../tests/fixtures/openai/usage.sse"),
+                "text/event-stream",
+            ))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let settings = Settings {
+            max_tokens: 10,
+            ..Default::default()
+        };
+        let mut chat = Chat::new("key", &server.uri(), "gpt-4", &settings).unwrap();
+        let mut tools = Tools::default();
+        tools.register(
+            "shell",
+            "Run a command",
+            json!({ "type": "object" }),
+            |_: Value| -> Result<String> { panic!("called during the summary") },
+        );
+        chat.set_tools(tools).unwrap();
+        chat.set_system("Be brief");
+        for turn in ["one", "two"] {
+            chat.build(Role::User, &format!("Question {turn}"));
+            chat.build(Role::Assistant, &"word ".repeat(300));
+        }
+        chat.build(Role::User, "Question three");
+        chat.build(Role::Assistant, "Answer three");
+        chat.build(Role::User, "Last question");
+
+        // Room for the last two turns and the summary
+        let window = Window::new(Some(450), Policy::Summarize);
+        let usage = Usage {
+            prompt_tokens: 9,
+            completion_tokens: 2,
+            total_tokens: 11,
+        };
+        assert_eq!(
+            window.fit(&mut chat).await.unwrap(),
+            Some(Fitted::Summarized {
+                count: 4,
+                model: "gpt-4o-2024-05-13".to_string(),
+                usage: Some(usage),
+            })
+        );
+        let contents = chat
+            .history()
+            .iter()
+            .map(|data| data.content.as_deref().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            contents,
+            [
+                "Be brief",
+                RECALL,
+                "Hi!",
+                "Question three",
+                "Answer three",
+                "Last question"
+            ]
+        );
+
+        // The summary was asked for without tools, which are back afterwards
+        let requests = server.received_requests().await.unwrap();
+        let body: Value = serde_json::from_slice(&requests[0].body).unwrap();
+        assert_eq!(body.get("tools"), None);
+        assert_eq!(body["messages"][0]["content"], SUMMARIZE);
+        assert!(!chat.take_tools().is_empty());
+
+        // The transcript keeps to the budget, the oldest messages left out
+        let transcript = body["messages"][1]["content"].as_str().unwrap();
+        assert!(transcript.starts_with("user: Question two"), "{transcript}");
+        assert!(tokens("gpt-4", &[Data::new(Role::User, transcript)]) <= 440);
+    }
+}
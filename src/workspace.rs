@@ -0,0 +1,461 @@
+use std::{
+    fmt::Write,
+    fs,
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use regex::Regex;
+use serde_json::{json, Value};
+use tokio::process::Command;
+
+use crate::context;
+use crate::tools::{Group, Handler, Tools};
+
+/// Bytes of file content or command output returned to the model.
+const MAX_OUTPUT: usize = 64 * 1024;
+
+/// Largest file read or searched, bigger ones are likely logs or data the
+/// model should not get whole anyway.
+const MAX_FILE: u64 = 1024 * 1024;
+
+/// Lines returned by a single search.
+const MAX_MATCHES: usize = 200;
+
+/// How long a shell command can run before it is killed.
+const TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Interaction with the user, needed by tools with side effects.
+pub trait Console: Send + Sync {
+    /// Ask whether the command can be run. The answer is no longer needed
+    /// once `cancelled` is set, e.g. when the turn was cancelled, and should
+    /// not be waited for then.
+    fn confirm(&self, command: &str, cancelled: &AtomicBool) -> bool;
+
+    /// Show the output of a command.
+    fn output(&self, text: &str);
+}
+
+/// Register the tools working on the files under `root`.
+pub fn register(tools: &mut Tools, root: &Path, console: Arc<dyn Console>) -> Result<()> {
+    let root = root.canonicalize()?;
+
+    let dir = root.clone();
+    tools.register(
+        "read_file",
+        "Read a text file, or a range of its lines",
+        json!({
+            "type": "object",
+            "properties": {
+                "path": { "type": "string", "description": "Path relative to the workspace root" },
+                "start_line": { "type": "integer", "description": "First line to read, from 1" },
+                "end_line": { "type": "integer", "description": "Last line to read, included" }
+            },
+            "required": ["path"]
+        }),
+        move |arguments: Value| read_file(&dir, &arguments),
+    );
+
+    let dir = root.clone();
+    tools.register(
+        "list_dir",
+        "List the files and directories in a directory",
+        json!({
+            "type": "object",
+            "properties": {
+                "path": { "type": "string", "description": "Path relative to the workspace root" }
+            }
+        }),
+        move |arguments: Value| list_dir(&dir, &arguments),
+    );
+
+    let dir = root.clone();
+    tools.register(
+        "grep",
+        "Search the files under a path for lines matching a regular expression",
+        json!({
+            "type": "object",
+            "properties": {
+                "pattern": { "type": "string", "description": "Regular expression" },
+                "path": { "type": "string", "description": "File or directory to search in" }
+            },
+            "required": ["pattern"]
+        }),
+        move |arguments: Value| grep(&dir, &arguments),
+    );
+
+    tools.register(
+        "shell",
+        "Run a shell command in the workspace root, the user is asked to confirm it",
+        json!({
+            "type": "object",
+            "properties": {
+                "command": { "type": "string", "description": "Command line run by sh -c" }
+            },
+            "required": ["command"]
+        }),
+        Shell {
+            root,
+            console,
+            timeout: TIMEOUT,
+        },
+    );
+
+    Ok(())
+}
+
+fn string<'a>(arguments: &'a Value, name: &str) -> Result<&'a str> {
+    arguments[name]
+        .as_str()
+        .ok_or(anyhow!("Missing {name} argument"))
+}
+
+/// Resolve `path` under `root`, refusing anything outside of it.
+fn resolve(root: &Path, path: &str) -> Result<PathBuf> {
+    let resolved = root
+        .join(path)
+        .canonicalize()
+        .map_err(|e| anyhow!("{path}: {e}"))?;
+
+    if !resolved.starts_with(root) {
+        return Err(anyhow!("{path} is outside of the workspace"));
+    }
+    Ok(resolved)
+}
+
+fn relative<'a>(root: &Path, path: &'a Path) -> &'a Path {
+    path.strip_prefix(root).unwrap_or(path)
+}
+
+fn truncate(mut text: String) -> String {
+    if text.len() > MAX_OUTPUT {
+        let mut end = MAX_OUTPUT;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        text.truncate(end);
+        text.push_str("\n[truncated]");
+    }
+    text
+}
+
+/// Whether the file is small enough to be read at once.
+fn fits(path: &Path) -> Result<bool> {
+    Ok(fs::metadata(path)?.len() <= MAX_FILE)
+}
+
+fn read_file(root: &Path, arguments: &Value) -> Result<String> {
+    let name = string(arguments, "path")?;
+    let path = resolve(root, name)?;
+    if !fits(&path)? {
+        return Err(anyhow!(
+            "{name} is larger than {}, search it with grep instead",
+            context::size(MAX_FILE as usize)
+        ));
+    }
+    let content = fs::read_to_string(&path)?;
+
+    let start = arguments["start_line"].as_u64().unwrap_or(1).max(1) as usize;
+    let end = arguments["end_line"].as_u64().map(|end| end as usize);
+
+    let lines = content
+        .lines()
+        .skip(start - 1)
+        .take(end.map_or(usize::MAX, |end| (end + 1).saturating_sub(start)))
+        .collect::<Vec<_>>();
+
+    Ok(truncate(lines.join("\n")))
+}
+
+fn list_dir(root: &Path, arguments: &Value) -> Result<String> {
+    let path = resolve(root, arguments["path"].as_str().unwrap_or("."))?;
+
+    let mut entries = fs::read_dir(&path)?
+        .map(|entry| {
+            let entry = entry?;
+            let mut name = entry.file_name().to_string_lossy().to_string();
+            if entry.file_type()?.is_dir() {
+                name.push('/');
+            }
+            Ok(name)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    entries.sort();
+
+    Ok(entries.join("\n"))
+}
+
+fn grep(root: &Path, arguments: &Value) -> Result<String> {
+    let regex = Regex::new(string(arguments, "pattern")?)?;
+    let path = resolve(root, arguments["path"].as_str().unwrap_or("."))?;
+
+    let mut files = vec![];
+    context::walk(&path, &mut files)?;
+
+    let mut matches = String::new();
+    let mut count = 0;
+    for file in files {
+        // Symlinks may lead out of the workspace, only the root is checked
+        // by `resolve`
+        let Ok(file) = file.canonicalize() else {
+            continue;
+        };
+        if !file.starts_with(root) || !fits(&file).unwrap_or(false) {
+            continue;
+        }
+
+        // Skip binaries and anything not readable as text
+        let Ok(content) = fs::read_to_string(&file) else {
+            continue;
+        };
+
+        for (number, line) in content.lines().enumerate() {
+            if regex.is_match(line) {
+                count += 1;
+                if count > MAX_MATCHES {
+                    matches.push_str("[more matches omitted]\n");
+                    return Ok(truncate(matches));
+                }
+                writeln!(
+                    matches,
+                    "{}:{}: {}",
+                    relative(root, &file).display(),
+                    number + 1,
+                    line
+                )?;
+            }
+        }
+    }
+
+    if matches.is_empty() {
+        return Ok("No matches".to_string());
+    }
+    Ok(truncate(matches))
+}
+
+/// Tells the console to stop waiting for an answer once dropped.
+#[derive(Default)]
+struct Cancel(Arc<AtomicBool>);
+
+impl Drop for Cancel {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Runs the commands confirmed by the user in the workspace root.
+struct Shell {
+    root: PathBuf,
+    console: Arc<dyn Console>,
+    timeout: Duration,
+}
+
+#[async_trait]
+impl Handler for Shell {
+    async fn call(&self, arguments: Value) -> Result<String> {
+        let command = string(&arguments, "command")?.to_string();
+
+        // Waiting for the answer blocks, keep it off the runtime, and stop
+        // waiting if the call is dropped
+        let (console, asked) = (self.console.clone(), command.clone());
+        let cancel = Cancel::default();
+        let cancelled = cancel.0.clone();
+        let confirm = move || console.confirm(&asked, &cancelled);
+        if !tokio::task::spawn_blocking(confirm).await? {
+            return Ok("The user refused to run the command".to_string());
+        }
+
+        let (child, mut group) = Group::spawn(
+            Command::new("sh")
+                .arg("-c")
+                .arg(&command)
+                .current_dir(&self.root)
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .kill_on_drop(true),
+        )?;
+
+        let output = tokio::time::timeout(self.timeout, child.wait_with_output())
+            .await
+            .map_err(|_| anyhow!("`{command}` timed out after {:?}", self.timeout))??;
+        group.release();
+
+        let mut text = String::from_utf8_lossy(&output.stdout).to_string();
+        text.push_str(&String::from_utf8_lossy(&output.stderr));
+        self.console.output(&text);
+
+        match output.status.code() {
+            Some(0) => Ok(truncate(text)),
+            Some(code) => Ok(truncate(format!("{text}\n[exit code {code}]"))),
+            None => Ok(truncate(format!("{text}\n[terminated by a signal]"))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    struct Scripted {
+        answer: bool,
+        wait: bool,
+        shown: Mutex<String>,
+    }
+
+    impl Console for Scripted {
+        fn confirm(&self, _command: &str, cancelled: &AtomicBool) -> bool {
+            // Never answered, until the call is dropped
+            if self.wait {
+                while !cancelled.load(Ordering::Relaxed) {
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                self.shown.lock().unwrap().push_str("gave up");
+                return false;
+            }
+            self.answer
+        }
+
+        fn output(&self, text: &str) {
+            self.shown.lock().unwrap().push_str(text);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_workspace_tools() {
+        let root = std::env::temp_dir().join(format!("octo-workspace-{}", std::process::id()));
+        fs::create_dir_all(root.join("src")).unwrap();
+        fs::write(
+            root.join("src/lib.rs"),
+            "fn one() {}\nfn two() {}\nfn three() {}\n",
+        )
+        .unwrap();
+
+        let console = Arc::new(Scripted {
+            answer: true,
+            wait: false,
+            shown: Mutex::new(String::new()),
+        });
+        let mut tools = Tools::default();
+        register(&mut tools, &root, console.clone()).unwrap();
+
+        let call = |name: &'static str, arguments: Value| {
+            let tools = tools.clone();
+            async move { tools.call(name, &arguments.to_string()).await }
+        };
+
+        assert_eq!(call("list_dir", json!({})).await, "src/");
+        assert_eq!(
+            call(
+                "read_file",
+                json!({ "path": "src/lib.rs", "start_line": 2, "end_line": 3 })
+            )
+            .await,
+            "fn two() {}\nfn three() {}"
+        );
+        assert_eq!(
+            call("grep", json!({ "pattern": "t[wh]" })).await,
+            "src/lib.rs:2: fn two() {}\nsrc/lib.rs:3: fn three() {}\n"
+        );
+        assert!(call("read_file", json!({ "path": "../../etc/passwd" }))
+            .await
+            .starts_with("Error:"));
+
+        // Not even through a symlink, nor too big files
+        let outside = std::env::temp_dir().join(format!("octo-secret-{}", std::process::id()));
+        fs::write(&outside, "fn secret() {}\n").unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("src/link.rs")).unwrap();
+        assert!(call("read_file", json!({ "path": "src/link.rs" }))
+            .await
+            .starts_with("Error:"));
+        assert_eq!(
+            call("grep", json!({ "pattern": "secret" })).await,
+            "No matches"
+        );
+        fs::remove_file(&outside).unwrap();
+        fs::write(
+            root.join("big.log"),
+            "fn big() {}\n".repeat(MAX_FILE as usize / 10),
+        )
+        .unwrap();
+        assert!(call("read_file", json!({ "path": "big.log" }))
+            .await
+            .contains("larger than"));
+        assert_eq!(
+            call("grep", json!({ "pattern": "big" })).await,
+            "No matches"
+        );
+        assert_eq!(call("shell", json!({ "command": "echo hi" })).await, "hi\n");
+        assert_eq!(*console.shown.lock().unwrap(), "hi\n");
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_refused_commands() {
+        let root = std::env::temp_dir().join(format!("octo-refused-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+
+        let console = Arc::new(Scripted {
+            answer: false,
+            wait: false,
+            shown: Mutex::new(String::new()),
+        });
+        let mut tools = Tools::default();
+        register(&mut tools, &root, console.clone()).unwrap();
+
+        let command = json!({ "command": "touch ran" }).to_string();
+        assert_eq!(
+            tools.call("shell", &command).await,
+            "The user refused to run the command"
+        );
+        assert!(!root.join("ran").exists(), "the command was run");
+        assert_eq!(*console.shown.lock().unwrap(), "");
+
+        // Commands running for too long are killed
+        let shell = Shell {
+            root: root.clone(),
+            console: Arc::new(Scripted {
+                answer: true,
+                wait: false,
+                shown: Mutex::new(String::new()),
+            }),
+            timeout: Duration::from_millis(200),
+        };
+        let error = shell
+            .call(json!({ "command": "sleep 5" }))
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "`sleep 5` timed out after 200ms");
+
+        // A cancelled turn stops waiting for the answer
+        let console = Arc::new(Scripted {
+            answer: true,
+            wait: true,
+            shown: Mutex::new(String::new()),
+        });
+        let shell = Shell {
+            root: root.clone(),
+            console: console.clone(),
+            timeout: TIMEOUT,
+        };
+        let call = shell.call(json!({ "command": "touch ran" }));
+        assert!(tokio::time::timeout(Duration::from_millis(100), call)
+            .await
+            .is_err());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(*console.shown.lock().unwrap(), "gave up");
+        assert!(!root.join("ran").exists(), "the command was run");
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
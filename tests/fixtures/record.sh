@@ -0,0 +1,58 @@
+#!/bin/sh
+# Record a streamed reply of each provider with a key in the environment,
+# redacted, next to the hand-written fixtures:
+#
+#   OPENAI_API_KEY=… ANTHROPIC_API_KEY=… GEMINI_API_KEY=… tests/fixtures/record.sh
+#
+# Ollama is recorded when it answers on localhost. Only the response body is
+# kept, the request headers carrying the keys never reach the files.
+set -eu
+
+cd "$(dirname "$0")"
+date=$(date -u +%Y-%m-%d)
+prompt='Count from one to three, in words.'
+
+# Response ids, fingerprints and timestamps are replaced by placeholders
+redact() {
+    sed -E \
+        -e 's/"id": ?"(chatcmpl|gen)-[A-Za-z0-9_-]+"/"id":"\1-1"/g' \
+        -e 's/"id": ?"(msg|toolu)_[A-Za-z0-9]+"/"id":"\1_01"/g' \
+        -e 's/"system_fingerprint": ?"[^"]*"/"system_fingerprint":"fp_1"/g' \
+        -e 's/"responseId": ?"[^"]*"/"responseId":"response-1"/g' \
+        -e 's/"(created|created_at)": ?[0-9]+/"\1":1700000000/g'
+}
+
+record() {
+    file=$1
+    shift
+    curl -sSN --fail-with-body "$@" | redact >"$file"
+    echo "recorded $file ($date)"
+}
+
+if [ -n "${OPENAI_API_KEY:-}" ]; then
+    record openai/captured.sse https://api.openai.com/v1/chat/completions \
+        -H "Authorization: Bearer $OPENAI_API_KEY" -H 'Content-Type: application/json' \
+        -d "{\"model\":\"gpt-4o-mini\",\"stream\":true,\"stream_options\":{\"include_usage\":true},
+             \"messages\":[{\"role\":\"user\",\"content\":\"$prompt\"}]}"
+fi
+
+if [ -n "${ANTHROPIC_API_KEY:-}" ]; then
+    record anthropic/captured.sse https://api.anthropic.com/v1/messages \
+        -H "x-api-key: $ANTHROPIC_API_KEY" -H 'anthropic-version: 2023-06-01' \
+        -H 'Content-Type: application/json' \
+        -d "{\"model\":\"claude-3-haiku-20240307\",\"max_tokens\":64,\"stream\":true,
+             \"messages\":[{\"role\":\"user\",\"content\":\"$prompt\"}]}"
+fi
+
+if [ -n "${GEMINI_API_KEY:-}" ]; then
+    record gemini/captured.sse \
+        'https://generativelanguage.googleapis.com/v1beta/models/gemini-1.5-flash:streamGenerateContent?alt=sse' \
+        -H "x-goog-api-key: $GEMINI_API_KEY" -H 'Content-Type: application/json' \
+        -d "{\"contents\":[{\"role\":\"user\",\"parts\":[{\"text\":\"$prompt\"}]}]}"
+fi
+
+if curl -s -o /dev/null http://localhost:11434/api/tags; then
+    record ollama/captured.ndjson http://localhost:11434/api/chat \
+        -d "{\"model\":\"${OLLAMA_MODEL:-llama3}\",
+             \"messages\":[{\"role\":\"user\",\"content\":\"$prompt\"}]}"
+fi